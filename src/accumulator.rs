@@ -2,6 +2,8 @@
 use hex_literal;
 use crate::crypto::hash::{H256, Hashable, H160};
 use crate::transaction::{Transaction, SignedTransaction, StateWitness};
+use crate::block::Block;
+use crate::script::Script;
 use std::collections::{HashMap, HashSet};
 use rand::{thread_rng, Rng};
 use modpow::modpow;
@@ -9,6 +11,7 @@ use modpow::modpow;
 use num_bigint::{BigInt,BigUint};
 use num_traits::One;
 use std::sync::Mutex;
+use serde::{Serialize, Deserialize};
 
 
 fn little_fermat(candidate: &u128) -> bool {
@@ -114,33 +117,42 @@ pub fn parameters() -> (u128, u128, u128) {
 	return (p,q,g)
 }
 
+// What a transaction changed in the accumulator, so that it can be undone on a reorg
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct TxUndo {
+	pub spent: Vec<((H256,u32),(u64,Script,u32))>, // removed entries, with their original prime
+	pub created: Vec<(H256,u32)>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Accumulator {
-	pub accumulator: HashMap<(H256,u32),(f32,H160,u32)>,// prev TX Hash, prev Output Index <-> Output Value, Recp Addr, Prime
+	pub accumulator: HashMap<(H256,u32),(u64,Script,u32)>,// prev TX Hash, prev Output Index <-> Output Value, Output Script, Prime
 	pub prime_set : HashSet<u32>,
 	pub n: u128,
 	pub g: u128,
+	pub undo: HashMap<H256,Vec<TxUndo>>, // Block Hash <-> undo data of its transactions, in block order
 }
 
 impl Accumulator {
 
 	pub fn new() -> Self {
-		let accumulator = HashMap::<(H256,u32),(f32,H160,u32)>::new(); //TX Hash, Output Index, Output Value, Recp Addr, Prime
+		let accumulator = HashMap::<(H256,u32),(u64,Script,u32)>::new(); //TX Hash, Output Index, Output Value, Output Script, Prime
 		let mut prime_set = HashSet::<u32>::new();
 		let (p, q, g) = parameters();
 	    let mut _n = p*q;
 	    let mut _g = g;
-		return Accumulator{accumulator: accumulator, prime_set: prime_set,  n: _n, g: _g,}
+		return Accumulator{accumulator: accumulator, prime_set: prime_set,  n: _n, g: _g, undo: HashMap::new(),}
 	}
 
-	pub fn hash_to_prime(&mut self, tx_hash: H256, output_index: u32,output_value:f32, recp_addr: H160 ){
+	pub fn hash_to_prime(&mut self, tx_hash: H256, output_index: u32,output_value:u64, script: Script ){
 		let mut rng = thread_rng();
 		let mut j:u32 = rng.gen_range(2, 10);
 		let prime = genprime_u32(j, 2u32.pow(j-1), 2u32.pow(j)-1);
 		if self.prime_set.contains(&prime){
-			self.hash_to_prime(tx_hash, output_index, output_value,recp_addr);
+			self.hash_to_prime(tx_hash, output_index, output_value,script);
 		}else{
 			self.prime_set.insert(prime);
-	    	self.accumulator.insert((tx_hash, output_index),(output_value,recp_addr, prime));
+	    	self.accumulator.insert((tx_hash, output_index),(output_value,script, prime));
 		}
 
 	}
@@ -171,6 +183,57 @@ impl Accumulator {
 		self.n = p*q;
 		self.g = g;
 	}
+
+	/// Spend the inputs and add the outputs of every transaction in the block, keeping undo data
+	pub fn connect_block(&mut self, block: &Block) {
+		let mut block_undo = Vec::<TxUndo>::new();
+		for content in block.Content.content.iter() {
+			let mut tx_undo = TxUndo::default();
+			for input in content.transaction.Input.iter() {
+				let key = (input.prevTransaction, input.preOutputIndex);
+				if let Some(entry) = self.accumulator.get(&key).cloned() {
+					self.delete_hash_prime(key.0, key.1, entry.2);
+					tx_undo.spent.push((key, entry));
+				}
+			}
+			for output in content.transaction.Output.iter() {
+				self.hash_to_prime(content.hash(), output.index, output.value, output.script.clone());
+				tx_undo.created.push((content.hash(), output.index));
+			}
+			block_undo.push(tx_undo);
+		}
+		self.undo.insert(block.hash(), block_undo);
+	}
+
+	/// Revert `connect_block`: drop the outputs the block created and restore the entries it spent
+	pub fn disconnect_block(&mut self, block_hash: &H256) {
+		let block_undo = match self.undo.remove(block_hash) {
+			Some(undo) => undo,
+			None => return,
+		};
+		for tx_undo in block_undo.iter().rev() {
+			for key in tx_undo.created.iter() {
+				if let Some(entry) = self.accumulator.get(key).cloned() {
+					self.delete_hash_prime(key.0, key.1, entry.2);
+				}
+			}
+			for (key, entry) in tx_undo.spent.iter() {
+				self.prime_set.insert(entry.2);
+				self.accumulator.insert(*key, entry.clone());
+			}
+		}
+	}
+
+	/// Record the accumulator proof for `block_hash` and recompute the witness of every state
+	pub fn update_state_witness(&self, stateWitness: &mut StateWitness, block_hash: H256) {
+		let A = self.accumulate();
+		stateWitness.AccumulatorProof.insert(block_hash, A);
+		stateWitness.States.clear();
+		for (key, values) in self.accumulator.iter() {
+			let witness = A / (values.2 as u128);
+			stateWitness.addStates(key.0, key.1, values.0, values.1.clone(), values.2, witness);
+		}
+	}
 /*
 	pub fn update(&mut self, SignedTransactions: &Vec<SignedTransaction>){
 	    for signedTransaction in SignedTransactions {
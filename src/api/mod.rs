@@ -1,11 +1,15 @@
 use serde::Serialize;
 use crate::miner::Handle as MinerHandle;
+use crate::network::banman::BanManager;
 use crate::network::server::Handle as NetworkServerHandle;
 use crate::network::message::Message;
+use crate::timedata;
 use crate::transaction::Handle as TransactionHandle;
 
 use log::info;
 use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use tiny_http::Header;
 use tiny_http::Response;
@@ -16,7 +20,8 @@ pub struct Server {
     handle: HTTPServer,
     miner: MinerHandle,
     network: NetworkServerHandle,
-    transaction:TransactionHandle
+    transaction:TransactionHandle,
+    banman: Arc<Mutex<BanManager>>,
 }
 
 #[derive(Serialize)]
@@ -25,6 +30,14 @@ struct ApiResponse {
     message: String,
 }
 
+/// An address to ban: `ip` for the whole address, or `ip:port` for the node listening on that port
+fn parse_ban_addr(addr: &str) -> Result<(IpAddr, Option<u16>), std::net::AddrParseError> {
+    match addr.parse::<SocketAddr>() {
+        Ok(addr) => Ok((addr.ip(), Some(addr.port()))),
+        Err(_) => addr.parse::<IpAddr>().map(|addr| (addr, None)),
+    }
+}
+
 macro_rules! respond_result {
     ( $req:expr, $success:expr, $message:expr ) => {{
         let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
@@ -44,6 +57,7 @@ impl Server {
         miner: &MinerHandle,
         network: &NetworkServerHandle,
         transaction: &TransactionHandle,
+        banman: &Arc<Mutex<BanManager>>,
     ) {
         let handle = HTTPServer::http(&addr).unwrap();
         let server = Self {
@@ -51,12 +65,14 @@ impl Server {
             miner: miner.clone(),
             network: network.clone(),
             transaction: transaction.clone(),
+            banman: Arc::clone(banman),
         };
         thread::spawn(move || {
             for req in server.handle.incoming_requests() {
                 let miner = server.miner.clone();
                 let network = server.network.clone();
                 let transaction = server.transaction.clone();
+                let banman = Arc::clone(&server.banman);
                 thread::spawn(move || {
                     // a valid url requires a base
                     let base_url = Url::parse(&format!("http://{}/", &addr)).unwrap();
@@ -92,6 +108,48 @@ impl Server {
                             miner.start(lambda);
                             respond_result!(req, true, "ok");
                         }
+                        "/miner/pause" => {
+                            miner.pause();
+                            respond_result!(req, true, "ok");
+                        }
+                        "/miner/resume" => {
+                            miner.resume();
+                            respond_result!(req, true, "ok");
+                        }
+                        "/miner/step" => {
+                            miner.step();
+                            respond_result!(req, true, "ok");
+                        }
+                        "/miner/mine" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let blocks = match params.get("blocks") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing blocks");
+                                    return;
+                                }
+                            };
+                            let blocks = match blocks.parse::<u64>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing blocks: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            miner.mine(blocks);
+                            respond_result!(req, true, "ok");
+                        }
+                        "/miner/stats" => {
+                            let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
+                            let resp = Response::from_string(serde_json::to_string_pretty(&miner.stats()).unwrap())
+                                .with_header(content_type);
+                            req.respond(resp).unwrap();
+                        }
                         "/transaction/start" => {
                             let params = url.query_pairs();
                             let params: HashMap<_, _> = params.into_owned().collect();
@@ -121,6 +179,99 @@ impl Server {
                             network.broadcast(Message::Ping(String::from("Test ping")));
                             respond_result!(req, true, "ok");
                         }
+                        "/network/metrics" => {
+                            let metrics = network.metrics().clone();
+                            let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
+                            let resp = Response::from_string(serde_json::to_string_pretty(&metrics).unwrap())
+                                .with_header(content_type);
+                            req.respond(resp).unwrap();
+                        }
+                        "/network/bans" => {
+                            let bans = banman.lock().unwrap().list(timedata::local_time());
+                            let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
+                            let resp = Response::from_string(serde_json::to_string_pretty(&bans).unwrap())
+                                .with_header(content_type);
+                            req.respond(resp).unwrap();
+                        }
+                        "/network/ban" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let addr = match params.get("addr") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing addr");
+                                    return;
+                                }
+                            };
+                            let (addr, port) = match parse_ban_addr(addr) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing addr: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            let now = timedata::local_time();
+                            match params.get("duration").map(|v| v.parse::<u128>()) {
+                                Some(Ok(duration)) => {
+                                    let until = match duration.checked_mul(1000).and_then(|ms| now.checked_add(ms)) {
+                                        Some(v) => v,
+                                        None => {
+                                            respond_result!(req, false, "duration too long");
+                                            return;
+                                        }
+                                    };
+                                    banman.lock().unwrap().ban_until(addr, port, until, "banned over the API");
+                                }
+                                Some(Err(e)) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing duration: {}", e)
+                                    );
+                                    return;
+                                }
+                                None => {
+                                    banman.lock().unwrap().ban(addr, port, now, "banned over the API");
+                                }
+                            }
+                            for (peer, _) in network.peers() {
+                                if peer.ip() == addr && (port.is_none() || port == Some(peer.port())) {
+                                    network.disconnect(peer);
+                                }
+                            }
+                            respond_result!(req, true, "ok");
+                        }
+                        "/network/unban" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let addr = match params.get("addr") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing addr");
+                                    return;
+                                }
+                            };
+                            let (addr, port) = match parse_ban_addr(addr) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing addr: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            if banman.lock().unwrap().unban(&addr, port) {
+                                respond_result!(req, true, "ok");
+                            } else {
+                                respond_result!(req, false, "address is not banned");
+                            }
+                        }
                         _ => {
                             let content_type =
                                 "Content-Type: application/json".parse::<Header>().unwrap();
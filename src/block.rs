@@ -1,7 +1,9 @@
 use serde::{Serialize, Deserialize};
 use crate::crypto::hash::{H256, Hashable};
 use crate::crypto::merkle::{MerkleTree};
-use crate::transaction::{Transaction, SignedTransaction, generate_random_signed_transaction_};
+use crate::transaction::{Transaction, SignedTransaction, output, generate_random_signed_transaction_};
+use crate::params::ChainParams;
+use crate::script::Script;
 
 
 use rand::{thread_rng, Rng};
@@ -32,13 +34,19 @@ pub struct Block {
     pub Content: Content,
 }
 
-impl Hashable for Block {
+impl Hashable for Header {
     fn hash(&self) -> H256 {
-        let header_serialized = bincode::serialize(&self.Header).unwrap();
+        let header_serialized = bincode::serialize(&self).unwrap();
         return ring::digest::digest(&ring::digest::SHA256, &header_serialized).into();
     }
 }
 
+impl Hashable for Block {
+    fn hash(&self) -> H256 {
+        return self.Header.hash();
+    }
+}
+
 
 impl Block{
     pub fn getparent(&self) -> H256 {
@@ -54,15 +62,42 @@ impl Block{
     }
 }
 
+/// The genesis block of the chain described by `params`. It has no parent, is not mined, and carries a single
+/// unsigned transaction without inputs that pays out the genesis allocation, so every node derives the same hash.
+pub fn genesis_block(params: &ChainParams) -> Block {
+    let mut allocation = Transaction::default();
+    for (index, (address, value)) in params.genesis_allocation.iter().enumerate() {
+        allocation.Output.push(output{
+            script: Script::PayToPubkeyHash(*address),
+            value: *value,
+            index: index as u32,
+        });
+    }
+    let transaction = vec![SignedTransaction{transaction: allocation, unlocks: Vec::new()}];
+    let MerkleTree = MerkleTree::new(&transaction);
+
+    let newHeader = Header{
+        parent: H256::default(),
+        nonce: 0,
+        difficulty: params.initial_difficulty,
+        timestamp: params.genesis_timestamp,
+        merkleRoot: MerkleTree.root(),
+    };
+
+    let newContent = Content{
+        content: transaction,
+    };
+
+    return Block{
+        Header: newHeader,
+        Content: newContent,
+    };
+}
 
-// The difficulty here should also be modified if it is modified in transaction generator
 pub fn generate_random_block_(parent: &H256) -> Block {
     let mut nonce:u32 = thread_rng().gen();
     let mut timestamp = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_millis();
-    let mut bytes32 = [255u8;32];
-    bytes32[0]=1;
-    bytes32[1]=1;
-    let difficulty : H256 = bytes32.into();
+    let difficulty : H256 = ChainParams::default().initial_difficulty;
     let mut transaction = Vec::<SignedTransaction>::new();
     transaction.push(generate_random_signed_transaction_());
     let mut MerkleTree = MerkleTree::new(&transaction);
@@ -1,48 +1,394 @@
-use crate::block::Block;
+use crate::block::{Block, Header};
+use crate::blockstore::BlockStore;
 use crate::crypto::hash::{H256,Hashable};
-use crate::block::generate_random_block_;
-use ring::{digest};
+use crate::block::genesis_block;
+use crate::params::{self, ChainParams};
+use log::{error, info};
+use num_bigint::BigUint;
+use num_traits::One;
 extern crate rand;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
 
 pub struct Blockchain {
      pub Blocks: HashMap<H256,(Block, u32)>, //H256 - <Block,Height>
+     pub Headers: HashMap<H256,(Header, u32)>, //H256 - <Header,Height>, for every block and for headers whose block is not downloaded yet
      pub genesis_hash: H256,
      pub tip: (H256, u32),
+     pub best_header: (H256, u32), //last header of the header chain with the most work
+     pub Work: HashMap<H256, BigUint>, //H256 - cumulative work of the chain ending at this header
+     pub invalid: HashSet<H256>, //blocks found invalid and blocks built on them, which are never accepted again
+     pub params: ChainParams,
+     store: BlockStore,
+}
+
+/// Number of blocks whose median timestamp a new block has to exceed
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+/// Expected number of hashes needed to meet `difficulty`, i.e. 2^256 / (target + 1)
+pub fn block_work(difficulty: &H256) -> BigUint {
+    let target = BigUint::from_bytes_be(difficulty.as_ref());
+    (BigUint::one() << 256) / (target + BigUint::one())
 }
 
 
 
 impl Blockchain {
-    /// Create a new blockchain, only containing the genesis block
-    pub fn new() -> Self {
+    /// Open the blockchain stored in `data_dir`, or create one only containing the genesis block
+    pub fn new(data_dir: &Path, params: ChainParams) -> std::io::Result<Self> {
+        let mut store = BlockStore::open(data_dir)?;
         let mut Blocks:HashMap<H256,(Block, u32)> = HashMap::new();
-        let genesis_hash = <H256>::from(digest::digest(&digest::SHA256, &[0x00 as u8]));
-        let mut block = generate_random_block_(&genesis_hash);
-        Blocks.insert(genesis_hash,(block, 0));
-        let tip = (genesis_hash, 0);
-        return Blockchain {Blocks: Blocks,genesis_hash:genesis_hash, tip: tip,};
+        let mut Headers:HashMap<H256,(Header, u32)> = HashMap::new();
+        let mut Work:HashMap<H256, BigUint> = HashMap::new();
+        let stored = store.load_all()?;
+        let genesis = genesis_block(&params);
+        if stored.is_empty() {
+            let genesis_hash = genesis.hash();
+            let block = genesis;
+            store.append(&genesis_hash, 0, &block)?;
+            Work.insert(genesis_hash, block_work(&block.getdifficulty()));
+            Headers.insert(genesis_hash,(block.Header.clone(), 0));
+            Blocks.insert(genesis_hash,(block, 0));
+            let tip = (genesis_hash, 0);
+            return Ok(Blockchain {Blocks: Blocks,Headers: Headers,genesis_hash:genesis_hash, tip: tip, best_header: tip, Work: Work, invalid: HashSet::new(), params: params, store: store,});
+        }
+        // the first record is always the genesis block and parents are always stored before their children,
+        // so cumulative work can be rebuilt in file order; the tip is the first block seen with the most work.
+        // Blocks marked invalid and their descendants are left out
+        let mut invalid = store.load_invalid()?;
+        let genesis_hash = stored[0].0.hash;
+        if genesis_hash != genesis.hash() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("stored genesis {:?} does not match the chain parameters (expected {:?})", genesis_hash, genesis.hash()),
+            ));
+        }
+        let mut tip = (genesis_hash, 0);
+        for (entry, block) in stored {
+            if invalid.contains(&entry.hash) || (entry.hash != genesis_hash && invalid.contains(&block.getparent())) {
+                invalid.insert(entry.hash);
+                continue;
+            }
+            let parent_work = if entry.hash == genesis_hash {
+                BigUint::default()
+            } else {
+                Work.get(&block.getparent()).cloned().unwrap_or_default()
+            };
+            let work = parent_work + block_work(&block.getdifficulty());
+            if Work.get(&tip.0).map_or(true, |tip_work| *tip_work < work) {
+                tip = (entry.hash, entry.height);
+            }
+            Work.insert(entry.hash, work);
+            Headers.insert(entry.hash, (block.Header.clone(), entry.height));
+            Blocks.insert(entry.hash, (block, entry.height));
+        }
+        info!("Reopened blockchain in {:?} with {} blocks, tip at height {}", store.dir(), Blocks.len(), tip.1);
+        return Ok(Blockchain {Blocks: Blocks,Headers: Headers,genesis_hash:genesis_hash, tip: tip, best_header: tip, Work: Work, invalid: invalid, params: params, store: store,});
+    }
+
+    /// Remember blocks as invalid, also across restarts
+    fn mark_invalid(&mut self, invalid: &HashSet<H256>) {
+        let hashes: Vec<H256> = invalid.iter().cloned().collect();
+        if let Err(e) = self.store.mark_invalid(&hashes) {
+            error!("Error persisting invalid blocks {:?}: {}", hashes, e);
+        }
+        self.invalid.extend(hashes);
+    }
+
+    /// Whether `header` belongs to a block found invalid, or is built on one
+    pub fn is_invalid(&self, header: &Header) -> bool {
+        self.invalid.contains(&header.hash()) || self.invalid.contains(&header.parent)
+    }
+
+    /// Insert a header whose parent header is known. Returns false if the header was already known, or is
+    /// invalid; a header built on an invalid block is recorded as invalid itself
+    pub fn insert_header(&mut self, header: &Header) -> bool {
+        let hash = header.hash();
+        if self.Headers.contains_key(&hash) {
+            return false;
+        }
+        if self.is_invalid(header) {
+            self.invalid.insert(hash);
+            return false;
+        }
+        let h = self.Headers.get(&header.parent).unwrap().1 + 1;
+        let work = self.Work.get(&header.parent).unwrap() + block_work(&header.difficulty);
+        if self.Work.get(&self.best_header.0).unwrap() < &work {
+            self.best_header = (hash, h);
+        }
+        self.Work.insert(hash, work);
+        self.Headers.insert(hash, (header.clone(), h));
+        return true;
     }
 
-    /// Insert a block into blockchain and update block hash CODE
+    /// Forget the header of a block found invalid, along with the headers built on it, so that the block is not
+    /// downloaded again. The best header falls back to the known header with the most work
+    pub fn invalidate_header(&mut self, hash: &H256) {
+        if !self.Headers.contains_key(hash) || self.Blocks.contains_key(hash) {
+            return;
+        }
+        let mut invalid = HashSet::<H256>::new();
+        invalid.insert(*hash);
+        loop {
+            let descendants: Vec<H256> = self
+                .Headers
+                .iter()
+                .filter(|(h, (header, _))| invalid.contains(&header.parent) && !invalid.contains(*h))
+                .map(|(h, _)| *h)
+                .collect();
+            if descendants.is_empty() {
+                break;
+            }
+            invalid.extend(descendants);
+        }
+        for h in invalid.iter() {
+            self.Headers.remove(h);
+            self.Work.remove(h);
+        }
+        self.mark_invalid(&invalid);
+        if invalid.contains(&self.best_header.0) {
+            self.best_header = self.tip;
+            for (h, (_, height)) in self.Headers.iter() {
+                if self.Work.get(h).unwrap() > self.Work.get(&self.best_header.0).unwrap() {
+                    self.best_header = (*h, *height);
+                }
+            }
+        }
+    }
+
+    /// Forget a block found invalid when its branch was connected, along with its descendants, and fall back to the
+    /// known block with the most work. They are remembered as invalid, so that they are not accepted again. Returns
+    /// the new tip
+    pub fn invalidate_block(&mut self, hash: &H256) -> H256 {
+        let mut invalid = HashSet::<H256>::new();
+        invalid.insert(*hash);
+        loop {
+            let descendants: Vec<H256> = self
+                .Headers
+                .iter()
+                .filter(|(h, (header, _))| invalid.contains(&header.parent) && !invalid.contains(*h))
+                .map(|(h, _)| *h)
+                .collect();
+            if descendants.is_empty() {
+                break;
+            }
+            invalid.extend(descendants);
+        }
+        for h in invalid.iter() {
+            self.Blocks.remove(h);
+            self.Headers.remove(h);
+            self.Work.remove(h);
+        }
+        self.mark_invalid(&invalid);
+        self.tip = (self.genesis_hash, 0);
+        for (h, (_, height)) in self.Blocks.iter() {
+            if self.Work.get(h).unwrap() > self.Work.get(&self.tip.0).unwrap() {
+                self.tip = (*h, *height);
+            }
+        }
+        self.best_header = self.tip;
+        for (h, (_, height)) in self.Headers.iter() {
+            if self.Work.get(h).unwrap() > self.Work.get(&self.best_header.0).unwrap() {
+                self.best_header = (*h, *height);
+            }
+        }
+        return self.tip.0;
+    }
+
+    /// Insert a block into blockchain and update block hash CODE. Invalid blocks are neither stored nor inserted
     pub fn insert(&mut self, block: &Block) -> H256 {
+        if self.Blocks.contains_key(&block.hash()) {
+            return self.tip.0;
+        }
         let last_block = block.clone();
-        let h = self.Blocks.get(&(last_block.getparent())).as_ref().unwrap().1 + 1;
-        self.Blocks.insert(block.hash(), (last_block, h));
-        if self.tip.1 < h{
+        self.insert_header(&last_block.Header);
+        if self.invalid.contains(&block.hash()) {
+            return self.tip.0;
+        }
+        let h = self.Headers.get(&block.hash()).unwrap().1;
+        if let Err(e) = self.store.append(&block.hash(), h, &last_block) {
+            error!("Error persisting block {:?}: {}", block.hash(), e);
+        }
+        // switch to the new block only if its chain has strictly more work than the current tip
+        if self.Work.get(&self.tip.0).unwrap() < self.Work.get(&block.hash()).unwrap() {
             self.tip = (block.hash(), h);
-
         }
+        self.Blocks.insert(block.hash(), (last_block, h));
         // self.tip = block.hash();
         return self.tip.0;
     }
 
-    /// Get the last block's hash of the longest chain
+    /// The directory the blocks are stored in
+    pub fn data_dir(&self) -> &Path {
+        self.store.dir()
+    }
+
+    /// Get the last block's hash of the chain with the most work
     pub fn tip(&self) -> H256 {
         return self.tip.0;
     }
 
+    /// The target a child of `parent` has to meet. It changes every `retarget_interval` blocks, scaled by how
+    /// long the last window took compared to `block_interval`
+    pub fn next_difficulty(&self, parent: &H256) -> H256 {
+        let (parent_header, parent_height) = self.Headers.get(parent).unwrap();
+        let interval = self.params.retarget_interval;
+        if interval == 0 || (parent_height + 1) % interval != 0 {
+            return parent_header.difficulty;
+        }
+        // measure the window ending at the parent, which spans `interval` blocks except right after genesis
+        let span = std::cmp::min(interval, *parent_height);
+        let first = self.ancestor(parent, parent_height - span);
+        let first_timestamp = self.Headers.get(&first).unwrap().0.timestamp;
+        let actual_span = parent_header.timestamp.saturating_sub(first_timestamp);
+        let expected_span = self.params.block_interval * span as u128;
+        return params::retarget(&parent_header.difficulty, actual_span, expected_span, &self.params);
+    }
+
+    /// Coinbases in `parent` and its ancestors that a child of `parent` may not spend yet
+    pub fn immature_coinbases(&self, parent: &H256) -> HashSet<H256> {
+        let mut immature = HashSet::<H256>::new();
+        let mut hash = *parent;
+        for _ in 1..self.params.coinbase_maturity {
+            let (block, height) = self.Blocks.get(&hash).unwrap();
+            if let Some(first) = block.Content.content.first() {
+                if first.transaction.isCoinbase() {
+                    immature.insert(first.hash());
+                }
+            }
+            if *height == 0 {
+                break;
+            }
+            hash = block.getparent();
+        }
+        immature
+    }
+
+    /// Transactions of `parent` and its ancestors, `depth` blocks in total, with the height they were confirmed at
+    pub fn recent_transactions(&self, parent: &H256, depth: u32) -> HashMap<H256, u32> {
+        let mut recent = HashMap::<H256, u32>::new();
+        let mut hash = *parent;
+        for _ in 0..depth {
+            let (block, height) = self.Blocks.get(&hash).unwrap();
+            for content in block.Content.content.iter() {
+                recent.insert(content.hash(), *height);
+            }
+            if *height == 0 {
+                break;
+            }
+            hash = block.getparent();
+        }
+        recent
+    }
+
+    /// Median timestamp of `block` and its ancestors, up to `MEDIAN_TIME_SPAN` blocks in total
+    pub fn median_time_past(&self, block: &H256) -> u128 {
+        let mut timestamps = Vec::<u128>::new();
+        let mut hash = *block;
+        loop {
+            let (header, height) = self.Headers.get(&hash).unwrap();
+            timestamps.push(header.timestamp);
+            if *height == 0 || timestamps.len() == MEDIAN_TIME_SPAN {
+                break;
+            }
+            hash = header.parent;
+        }
+        timestamps.sort();
+        return timestamps[timestamps.len() / 2];
+    }
+
+    /// Walk back from `from` and `to` to their fork point. Returns the blocks to disconnect (from `from`
+    /// down to just above the fork point) and the blocks to connect (from just above the fork point up to `to`)
+    pub fn fork_path(&self, from: &H256, to: &H256) -> (Vec<H256>, Vec<H256>) {
+        let mut disconnect = Vec::<H256>::new();
+        let mut connect = Vec::<H256>::new();
+        let mut old = *from;
+        let mut new = *to;
+        while self.Blocks.get(&old).unwrap().1 > self.Blocks.get(&new).unwrap().1 {
+            disconnect.push(old);
+            old = self.Blocks.get(&old).unwrap().0.getparent();
+        }
+        while self.Blocks.get(&new).unwrap().1 > self.Blocks.get(&old).unwrap().1 {
+            connect.push(new);
+            new = self.Blocks.get(&new).unwrap().0.getparent();
+        }
+        while old != new {
+            disconnect.push(old);
+            connect.push(new);
+            old = self.Blocks.get(&old).unwrap().0.getparent();
+            new = self.Blocks.get(&new).unwrap().0.getparent();
+        }
+        connect.reverse();
+        return (disconnect, connect);
+    }
+
+    /// The ancestor of the header `hash` at `height`
+    pub fn ancestor(&self, hash: &H256, height: u32) -> H256 {
+        let mut hash = *hash;
+        while self.Headers.get(&hash).unwrap().1 > height {
+            hash = self.Headers.get(&hash).unwrap().0.parent;
+        }
+        return hash;
+    }
+
+    /// A block locator for the best header chain: its last ten hashes, then hashes exponentially further
+    /// apart, always ending with the genesis block
+    pub fn locator(&self) -> Vec<H256> {
+        let mut locator = Vec::<H256>::new();
+        let (mut hash, mut height) = self.best_header;
+        let mut step = 1;
+        loop {
+            locator.push(hash);
+            if height == 0 {
+                break;
+            }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
+            hash = self.ancestor(&hash, height);
+        }
+        return locator;
+    }
+
+    /// Up to `max` headers of the main chain, following the first hash of `locator` that is on it
+    /// (or the genesis block if none is)
+    pub fn headers_after(&self, locator: &[H256], max: usize) -> Vec<Header> {
+        let mut fork = self.genesis_hash;
+        for hash in locator.iter() {
+            if let Some((_, height)) = self.Blocks.get(hash) {
+                if *height <= self.tip.1 && self.ancestor(&self.tip.0, *height) == *hash {
+                    fork = *hash;
+                    break;
+                }
+            }
+        }
+        let mut headers = Vec::<Header>::new();
+        let mut hash = self.tip.0;
+        while hash != fork {
+            let header = &self.Headers.get(&hash).unwrap().0;
+            headers.push(header.clone());
+            hash = header.parent;
+        }
+        headers.reverse();
+        headers.truncate(max);
+        return headers;
+    }
+
+    /// Up to `max` blocks of the best header chain that are not downloaded yet, oldest first
+    pub fn missing_blocks(&self, max: usize) -> Vec<H256> {
+        let mut missing = Vec::<H256>::new();
+        let mut hash = self.best_header.0;
+        while !self.Blocks.contains_key(&hash) {
+            missing.push(hash);
+            hash = self.Headers.get(&hash).unwrap().0.parent;
+        }
+        missing.reverse();
+        missing.truncate(max);
+        return missing;
+    }
+
     /// Get the last block's hash of the longest chain
     //#[cfg(any(test, test_utilities))]
     pub fn all_blocks_in_longest_chain(&self) -> Vec<H256> {
@@ -60,5 +406,278 @@ impl Blockchain {
     }
 }
 
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::block::generate_random_block_;
+    use crate::crypto::hash::tests::generate_random_hash;
+    use std::path::PathBuf;
+
+    pub fn temp_data_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("blockchain-{}-{}", name, generate_random_hash()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn insert_one() {
+        let dir = temp_data_dir("insert_one");
+        let mut blockchain = Blockchain::new(&dir, ChainParams::default()).unwrap();
+        let genesis_hash = blockchain.tip();
+        let block = generate_random_block_(&genesis_hash);
+        blockchain.insert(&block);
+        assert_eq!(blockchain.tip(), block.hash());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn deterministic_genesis() {
+        let dir_a = temp_data_dir("genesis_a");
+        let dir_b = temp_data_dir("genesis_b");
+        let a = Blockchain::new(&dir_a, ChainParams::default()).unwrap();
+        let b = Blockchain::new(&dir_b, ChainParams::default()).unwrap();
+        assert_eq!(a.genesis_hash, b.genesis_hash);
+        assert_eq!(a.Blocks.get(&a.genesis_hash).unwrap().0.hash(), a.genesis_hash);
+        // a data directory of another chain is refused
+        drop(a);
+        let params = ChainParams { genesis_timestamp: 0, ..ChainParams::default() };
+        assert!(Blockchain::new(&dir_a, params).is_err());
+        std::fs::remove_dir_all(&dir_a).unwrap();
+        std::fs::remove_dir_all(&dir_b).unwrap();
+    }
+
+    #[test]
+    fn longest_chain() {
+        let dir = temp_data_dir("longest_chain");
+        let mut blockchain = Blockchain::new(&dir, ChainParams::default()).unwrap();
+        let genesis_hash = blockchain.tip();
+        let a1 = generate_random_block_(&genesis_hash);
+        let b1 = generate_random_block_(&genesis_hash);
+        let b2 = generate_random_block_(&b1.hash());
+        blockchain.insert(&a1);
+        blockchain.insert(&b1);
+        assert_eq!(blockchain.tip(), a1.hash());
+        blockchain.insert(&b2);
+        assert_eq!(blockchain.tip(), b2.hash());
+        assert_eq!(blockchain.all_blocks_in_longest_chain(), vec![genesis_hash, b1.hash(), b2.hash()]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 
+    #[test]
+    fn most_work_chain() {
+        let dir = temp_data_dir("most_work_chain");
+        let mut blockchain = Blockchain::new(&dir, ChainParams::default()).unwrap();
+        let genesis_hash = blockchain.tip();
+        let a1 = generate_random_block_(&genesis_hash);
+        let a2 = generate_random_block_(&a1.hash());
+        // a single block mined at a quarter of the target outweighs two blocks at the default target
+        let mut b1 = generate_random_block_(&genesis_hash);
+        let mut target = [0u8; 32];
+        target.copy_from_slice(b1.getdifficulty().as_ref());
+        target[0] = 0;
+        target[1] = 0x40;
+        b1.Header.difficulty = target.into();
+        blockchain.insert(&a1);
+        blockchain.insert(&a2);
+        assert_eq!(blockchain.tip(), a2.hash());
+        blockchain.insert(&b1);
+        assert_eq!(blockchain.tip(), b1.hash());
+        assert_eq!(blockchain.tip.1, 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 
+    #[test]
+    fn retarget_every_interval() {
+        let dir = temp_data_dir("retarget");
+        let params = ChainParams { retarget_interval: 4, ..ChainParams::default() };
+        let mut blockchain = Blockchain::new(&dir, params.clone()).unwrap();
+        let mut parent = blockchain.tip();
+        let start = blockchain.Blocks.get(&parent).unwrap().0.gettimestamp();
+        // blocks come twice as fast as desired, so the target halves after the third block
+        for height in 1..4u128 {
+            assert_eq!(blockchain.next_difficulty(&parent), params.initial_difficulty);
+            let mut block = generate_random_block_(&parent);
+            block.Header.difficulty = blockchain.next_difficulty(&parent);
+            block.Header.timestamp = start + height * params.block_interval / 2;
+            blockchain.insert(&block);
+            parent = block.hash();
+        }
+        let expected = params::retarget(&params.initial_difficulty, 1, 2, &params);
+        assert_eq!(blockchain.next_difficulty(&parent), expected);
+        assert!(expected < params.initial_difficulty);
+        // the adjustment is clamped by the retarget factor and the pow limit
+        assert_eq!(params::retarget(&params.initial_difficulty, 1000, 1, &params), params::retarget(&params.initial_difficulty, 4, 1, &params));
+        assert_eq!(params::retarget(&params.pow_limit, 2, 1, &params), params.pow_limit);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn median_time_past() {
+        let dir = temp_data_dir("median_time_past");
+        let mut blockchain = Blockchain::new(&dir, ChainParams::default()).unwrap();
+        let genesis_hash = blockchain.tip();
+        let start = blockchain.Blocks.get(&genesis_hash).unwrap().0.gettimestamp();
+        assert_eq!(blockchain.median_time_past(&genesis_hash), start);
+        // timestamps of heights 1..=12 are start + 10, 20, ..., 120, with height 6 claiming to be far ahead
+        let mut parent = genesis_hash;
+        for height in 1..=12u128 {
+            let mut block = generate_random_block_(&parent);
+            block.Header.timestamp = if height == 6 { start + 1_000_000 } else { start + height * 10 };
+            blockchain.insert(&block);
+            parent = block.hash();
+        }
+        // the last 11 blocks are heights 2..=12, whose median is height 8 once the outlier sorts last
+        assert_eq!(blockchain.median_time_past(&parent), start + 80);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn headers_first() {
+        let dir_a = temp_data_dir("headers_a");
+        let dir_b = temp_data_dir("headers_b");
+        let mut a = Blockchain::new(&dir_a, ChainParams::default()).unwrap();
+        let mut b = Blockchain::new(&dir_b, ChainParams::default()).unwrap();
+        let mut chain = vec![a.genesis_hash];
+        for _ in 0..30 {
+            let block = generate_random_block_(chain.last().unwrap());
+            chain.push(block.hash());
+            a.insert(&block);
+        }
+        // ten consecutive hashes, then steps of 2, 4 and 8, then genesis
+        let locator = a.locator();
+        assert_eq!(locator[..10].to_vec(), chain[21..].iter().rev().cloned().collect::<Vec<H256>>());
+        assert_eq!(locator[10..].to_vec(), vec![chain[19], chain[15], chain[7], chain[0]]);
+
+        // b only knows genesis, so it is sent every header from there, then fetches the blocks
+        let headers = a.headers_after(&b.locator(), 2000);
+        assert_eq!(headers.len(), 30);
+        assert_eq!(a.headers_after(&b.locator(), 10).len(), 10);
+        for header in headers.iter() {
+            assert!(b.insert_header(header));
+        }
+        assert_eq!(b.best_header, a.tip);
+        assert_eq!(b.tip(), b.genesis_hash);
+        assert_eq!(b.missing_blocks(5), chain[1..6].to_vec());
+        for hash in chain[1..].iter() {
+            b.insert(&a.Blocks.get(hash).unwrap().0);
+        }
+        assert_eq!(b.tip(), a.tip());
+        assert!(b.missing_blocks(5).is_empty());
+        // a locator from a stale branch continues from the fork point
+        let stale = generate_random_block_(&chain[5]);
+        b.insert(&stale);
+        let mut locator = vec![stale.hash()];
+        locator.extend_from_slice(&chain[..6].iter().rev().cloned().collect::<Vec<H256>>());
+        let headers = a.headers_after(&locator, 2000);
+        assert_eq!(headers[0].hash(), chain[6]);
+        std::fs::remove_dir_all(&dir_a).unwrap();
+        std::fs::remove_dir_all(&dir_b).unwrap();
+    }
+
+    #[test]
+    fn invalidate_header() {
+        let dir = temp_data_dir("invalidate_header");
+        let mut blockchain = Blockchain::new(&dir, ChainParams::default()).unwrap();
+        let a1 = generate_random_block_(&blockchain.genesis_hash);
+        let b1 = generate_random_block_(&blockchain.genesis_hash);
+        let b2 = generate_random_block_(&b1.hash());
+        blockchain.insert(&a1);
+        blockchain.insert_header(&b1.Header);
+        blockchain.insert_header(&b2.Header);
+        assert_eq!(blockchain.best_header.0, b2.hash());
+        // b1's body turns out invalid: its branch is forgotten and a1 is the best header again
+        blockchain.invalidate_header(&b1.hash());
+        assert!(!blockchain.Headers.contains_key(&b2.hash()));
+        assert_eq!(blockchain.best_header, blockchain.tip);
+        assert!(blockchain.missing_blocks(10).is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn invalidate_block() {
+        let dir = temp_data_dir("invalidate_block");
+        let mut blockchain = Blockchain::new(&dir, ChainParams::default()).unwrap();
+        let a1 = generate_random_block_(&blockchain.genesis_hash);
+        let b1 = generate_random_block_(&blockchain.genesis_hash);
+        let b2 = generate_random_block_(&b1.hash());
+        blockchain.insert(&a1);
+        blockchain.insert(&b1);
+        blockchain.insert(&b2);
+        assert_eq!(blockchain.tip(), b2.hash());
+        assert_eq!(blockchain.invalidate_block(&b1.hash()), a1.hash());
+        // the branch is not taken back when it is announced again, nor is anything built on it
+        let b3 = generate_random_block_(&b2.hash());
+        assert!(!blockchain.insert_header(&b2.Header));
+        assert_eq!(blockchain.insert(&b1), a1.hash());
+        assert_eq!(blockchain.insert(&b3), a1.hash());
+        assert!(!blockchain.Headers.contains_key(&b1.hash()) && !blockchain.Blocks.contains_key(&b3.hash()));
+        assert!(blockchain.is_invalid(&generate_random_block_(&b3.hash()).Header));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fork_path() {
+        let dir = temp_data_dir("fork_path");
+        let mut blockchain = Blockchain::new(&dir, ChainParams::default()).unwrap();
+        let genesis_hash = blockchain.tip();
+        let a1 = generate_random_block_(&genesis_hash);
+        let a2 = generate_random_block_(&a1.hash());
+        let b2 = generate_random_block_(&a1.hash());
+        let b3 = generate_random_block_(&b2.hash());
+        for block in [&a1, &a2, &b2, &b3].iter() {
+            blockchain.insert(block);
+        }
+        let (disconnect, connect) = blockchain.fork_path(&a2.hash(), &b3.hash());
+        assert_eq!(disconnect, vec![a2.hash()]);
+        assert_eq!(connect, vec![b2.hash(), b3.hash()]);
+        let (disconnect, connect) = blockchain.fork_path(&genesis_hash, &a2.hash());
+        assert!(disconnect.is_empty());
+        assert_eq!(connect, vec![a1.hash(), a2.hash()]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reopen_rebuilds_tip() {
+        let dir = temp_data_dir("reopen");
+        let (genesis_hash, tip) = {
+            let mut blockchain = Blockchain::new(&dir, ChainParams::default()).unwrap();
+            let genesis_hash = blockchain.tip();
+            let a1 = generate_random_block_(&genesis_hash);
+            let a2 = generate_random_block_(&a1.hash());
+            let b1 = generate_random_block_(&genesis_hash);
+            blockchain.insert(&a1);
+            blockchain.insert(&a2);
+            blockchain.insert(&b1);
+            (genesis_hash, blockchain.tip())
+        };
+        let blockchain = Blockchain::new(&dir, ChainParams::default()).unwrap();
+        assert_eq!(blockchain.genesis_hash, genesis_hash);
+        assert_eq!(blockchain.tip(), tip);
+        assert_eq!(blockchain.tip.1, 2);
+        assert_eq!(blockchain.Blocks.len(), 4);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reopen_skips_invalid_blocks() {
+        let dir = temp_data_dir("reopen_invalid");
+        let (a1, b1, b2) = {
+            let mut blockchain = Blockchain::new(&dir, ChainParams::default()).unwrap();
+            let a1 = generate_random_block_(&blockchain.genesis_hash);
+            let b1 = generate_random_block_(&blockchain.genesis_hash);
+            let b2 = generate_random_block_(&b1.hash());
+            blockchain.insert(&a1);
+            blockchain.insert(&b1);
+            blockchain.insert(&b2);
+            blockchain.invalidate_block(&b1.hash());
+            (a1, b1, b2)
+        };
+        // the records of b1 and b2 are still in the block file, and still outweigh a1
+        let mut blockchain = Blockchain::new(&dir, ChainParams::default()).unwrap();
+        assert_eq!(blockchain.tip(), a1.hash());
+        assert!(!blockchain.Headers.contains_key(&b1.hash()) && !blockchain.Headers.contains_key(&b2.hash()));
+        assert_eq!(blockchain.insert(&b2), a1.hash());
+        assert!(blockchain.is_invalid(&generate_random_block_(&b2.hash()).Header));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,285 @@
+use crate::block::Block;
+use crate::crypto::hash::H256;
+use log::{info, warn};
+
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const BLOCK_FILE: &str = "blocks.dat";
+const INDEX_FILE: &str = "blocks.idx";
+// hashes of blocks found invalid, 32 bytes each, whose records stay in blocks.dat
+const INVALID_FILE: &str = "invalid.dat";
+
+// record in blocks.dat: [payload length u32][block hash 32][height u32][bincode block]
+const RECORD_PREFIX: usize = 4;
+const RECORD_HEADER: usize = 32 + 4;
+// entry in blocks.idx: [block hash 32][height u32][offset u64][record length u32]
+const INDEX_ENTRY: usize = 32 + 4 + 8 + 4;
+
+#[derive(Debug, Clone, Copy)]
+pub struct IndexEntry {
+    pub hash: H256,
+    pub height: u32,
+    pub offset: u64,
+    pub length: u32,
+}
+
+impl IndexEntry {
+    fn encode(&self) -> [u8; INDEX_ENTRY] {
+        let mut buffer = [0u8; INDEX_ENTRY];
+        buffer[0..32].copy_from_slice(self.hash.as_ref());
+        buffer[32..36].copy_from_slice(&self.height.to_be_bytes());
+        buffer[36..44].copy_from_slice(&self.offset.to_be_bytes());
+        buffer[44..48].copy_from_slice(&self.length.to_be_bytes());
+        buffer
+    }
+
+    fn decode(buffer: &[u8]) -> IndexEntry {
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(&buffer[0..32]);
+        IndexEntry {
+            hash: hash.into(),
+            height: u32::from_be_bytes(buffer[32..36].try_into().unwrap()),
+            offset: u64::from_be_bytes(buffer[36..44].try_into().unwrap()),
+            length: u32::from_be_bytes(buffer[44..48].try_into().unwrap()),
+        }
+    }
+}
+
+/// Append-only block file plus a hash/height index.
+///
+/// Blocks are appended to `blocks.dat` first and indexed in `blocks.idx` afterwards, so the block
+/// file is always the source of truth. On open, a torn tail in either file is truncated and any
+/// records missing from the index are re-indexed by scanning the block file.
+pub struct BlockStore {
+    dir: PathBuf,
+    blocks: File,
+    index: File,
+    blocks_len: u64,
+    pub entries: Vec<IndexEntry>,
+}
+
+impl BlockStore {
+    /// Open (or create) the store in `dir` and recover it into a consistent state.
+    pub fn open(dir: &Path) -> std::io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let mut blocks = OpenOptions::new().read(true).write(true).create(true).open(dir.join(BLOCK_FILE))?;
+        let mut index = OpenOptions::new().read(true).write(true).create(true).open(dir.join(INDEX_FILE))?;
+        let blocks_len = blocks.metadata()?.len();
+
+        // load the index, dropping a partially written entry and entries beyond the block file
+        let mut raw_index = Vec::<u8>::new();
+        index.read_to_end(&mut raw_index)?;
+        let mut entries = Vec::<IndexEntry>::new();
+        for chunk in raw_index.chunks_exact(INDEX_ENTRY) {
+            let entry = IndexEntry::decode(chunk);
+            if entry.offset + entry.length as u64 > blocks_len {
+                break;
+            }
+            entries.push(entry);
+        }
+        let index_len = (entries.len() * INDEX_ENTRY) as u64;
+        if index_len != raw_index.len() as u64 {
+            warn!("Block index in {:?} is inconsistent, truncating to {} entries", dir, entries.len());
+            index.set_len(index_len)?;
+        }
+
+        // re-index records that made it to the block file but not to the index
+        let mut offset = entries.last().map(|e| e.offset + e.length as u64).unwrap_or(0);
+        let mut recovered = 0;
+        blocks.seek(SeekFrom::Start(offset))?;
+        let mut rest = Vec::<u8>::new();
+        blocks.read_to_end(&mut rest)?;
+        let mut cursor = 0usize;
+        while rest.len() - cursor >= RECORD_PREFIX + RECORD_HEADER {
+            let payload_len = u32::from_be_bytes(rest[cursor..cursor + 4].try_into().unwrap()) as usize;
+            let record_len = RECORD_PREFIX + payload_len;
+            if payload_len < RECORD_HEADER || rest.len() - cursor < record_len {
+                break;
+            }
+            let record = &rest[cursor + RECORD_PREFIX..cursor + record_len];
+            if bincode::deserialize::<Block>(&record[RECORD_HEADER..]).is_err() {
+                break;
+            }
+            let mut hash = [0u8; 32];
+            hash.copy_from_slice(&record[0..32]);
+            let entry = IndexEntry {
+                hash: hash.into(),
+                height: u32::from_be_bytes(record[32..36].try_into().unwrap()),
+                offset,
+                length: record_len as u32,
+            };
+            index.seek(SeekFrom::End(0))?;
+            index.write_all(&entry.encode())?;
+            entries.push(entry);
+            offset += record_len as u64;
+            cursor += record_len;
+            recovered += 1;
+        }
+        if offset != blocks_len {
+            warn!("Block file in {:?} has a torn tail, truncating {} bytes", dir, blocks_len - offset);
+            blocks.set_len(offset)?;
+        }
+        if recovered > 0 {
+            info!("Re-indexed {} blocks from {:?}", recovered, dir);
+            index.sync_data()?;
+        }
+
+        Ok(BlockStore {
+            dir: dir.to_path_buf(),
+            blocks,
+            index,
+            blocks_len: offset,
+            entries,
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Read back the block referenced by an index entry.
+    pub fn read(&mut self, entry: &IndexEntry) -> std::io::Result<Block> {
+        let mut record = vec![0u8; entry.length as usize];
+        self.blocks.seek(SeekFrom::Start(entry.offset))?;
+        self.blocks.read_exact(&mut record)?;
+        bincode::deserialize(&record[RECORD_PREFIX + RECORD_HEADER..])
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+
+    /// Read every stored block, in the order they were appended.
+    pub fn load_all(&mut self) -> std::io::Result<Vec<(IndexEntry, Block)>> {
+        let entries = self.entries.clone();
+        let mut blocks = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let block = self.read(&entry)?;
+            blocks.push((entry, block));
+        }
+        Ok(blocks)
+    }
+
+    /// Append a block to the block file, then record it in the index.
+    pub fn append(&mut self, hash: &H256, height: u32, block: &Block) -> std::io::Result<()> {
+        let serialized = bincode::serialize(block).unwrap();
+        let payload_len = RECORD_HEADER + serialized.len();
+        let mut record = Vec::with_capacity(RECORD_PREFIX + payload_len);
+        record.extend_from_slice(&(payload_len as u32).to_be_bytes());
+        record.extend_from_slice(hash.as_ref());
+        record.extend_from_slice(&height.to_be_bytes());
+        record.extend_from_slice(&serialized);
+
+        self.blocks.seek(SeekFrom::Start(self.blocks_len))?;
+        self.blocks.write_all(&record)?;
+        self.blocks.sync_data()?;
+
+        let entry = IndexEntry {
+            hash: *hash,
+            height,
+            offset: self.blocks_len,
+            length: record.len() as u32,
+        };
+        self.index.seek(SeekFrom::End(0))?;
+        self.index.write_all(&entry.encode())?;
+        self.index.sync_data()?;
+
+        self.blocks_len += record.len() as u64;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// Record blocks found invalid, so that they are skipped when the store is reopened.
+    pub fn mark_invalid(&mut self, hashes: &[H256]) -> std::io::Result<()> {
+        let mut file = OpenOptions::new().append(true).create(true).open(self.dir.join(INVALID_FILE))?;
+        for hash in hashes.iter() {
+            file.write_all(hash.as_ref())?;
+        }
+        file.sync_data()
+    }
+
+    /// Hashes of the blocks marked invalid, ignoring a partially written one.
+    pub fn load_invalid(&self) -> std::io::Result<HashSet<H256>> {
+        let raw = match fs::read(self.dir.join(INVALID_FILE)) {
+            Ok(raw) => raw,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashSet::new()),
+            Err(e) => return Err(e),
+        };
+        Ok(raw
+            .chunks_exact(32)
+            .map(|chunk| {
+                let mut hash = [0u8; 32];
+                hash.copy_from_slice(chunk);
+                hash.into()
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::generate_random_block_;
+    use crate::crypto::hash::Hashable;
+    use crate::crypto::hash::tests::generate_random_hash;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("blockstore-{}-{}", name, generate_random_hash()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn reopen() {
+        let dir = temp_dir("reopen");
+        let parent = generate_random_hash();
+        let block = generate_random_block_(&parent);
+        {
+            let mut store = BlockStore::open(&dir).unwrap();
+            store.append(&block.hash(), 1, &block).unwrap();
+        }
+        let mut store = BlockStore::open(&dir).unwrap();
+        let blocks = store.load_all().unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].0.hash, block.hash());
+        assert_eq!(blocks[0].0.height, 1);
+        assert_eq!(blocks[0].1.hash(), block.hash());
+        // marks of invalid blocks survive too
+        assert!(store.load_invalid().unwrap().is_empty());
+        store.mark_invalid(&[block.hash()]).unwrap();
+        drop(store);
+        let store = BlockStore::open(&dir).unwrap();
+        assert!(store.load_invalid().unwrap().contains(&block.hash()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recover_torn_tail_and_missing_index() {
+        let dir = temp_dir("recover");
+        let first = generate_random_block_(&generate_random_hash());
+        let second = generate_random_block_(&first.hash());
+        {
+            let mut store = BlockStore::open(&dir).unwrap();
+            store.append(&first.hash(), 0, &first).unwrap();
+            store.append(&second.hash(), 1, &second).unwrap();
+        }
+        // lose the index entirely and tear the block file
+        fs::remove_file(dir.join(INDEX_FILE)).unwrap();
+        let mut blocks = OpenOptions::new().append(true).open(dir.join(BLOCK_FILE)).unwrap();
+        blocks.write_all(&[0, 0, 1, 0, 42]).unwrap();
+        drop(blocks);
+
+        let mut store = BlockStore::open(&dir).unwrap();
+        let blocks = store.load_all().unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[1].0.hash, second.hash());
+        assert_eq!(blocks[1].0.height, 1);
+        // the repaired store keeps accepting appends
+        let third = generate_random_block_(&second.hash());
+        store.append(&third.hash(), 2, &third).unwrap();
+        drop(store);
+        assert_eq!(BlockStore::open(&dir).unwrap().entries.len(), 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
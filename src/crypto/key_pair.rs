@@ -1,9 +1,23 @@
-use ring::rand;
+use super::hash::{H160, H256};
 use ring::signature::Ed25519KeyPair;
+use ring::{digest, rand};
 
 /// Generate a random key pair.
 pub fn random() -> Ed25519KeyPair {
+    from_pkcs8(&random_pkcs8())
+}
+
+/// Generate a random key pair in PKCS#8, to load it more than once.
+pub fn random_pkcs8() -> Vec<u8> {
     let rng = rand::SystemRandom::new();
-    let pkcs8_bytes = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
-    Ed25519KeyPair::from_pkcs8(pkcs8_bytes.as_ref().into()).unwrap()
+    Ed25519KeyPair::generate_pkcs8(&rng).unwrap().as_ref().to_vec()
+}
+
+pub fn from_pkcs8(pkcs8: &[u8]) -> Ed25519KeyPair {
+    Ed25519KeyPair::from_pkcs8(pkcs8).unwrap()
+}
+
+/// The address coins are paid to for a public key: the last 20 bytes of its SHA256.
+pub fn address(public_key: &[u8]) -> H160 {
+    <H160>::from(<H256>::from(digest::digest(&digest::SHA256, public_key)))
 }
@@ -5,26 +5,30 @@ extern crate hex_literal;
 pub mod api;
 pub mod block;
 pub mod blockchain;
+pub mod blockstore;
 pub mod crypto;
 pub mod miner;
 pub mod network;
+pub mod params;
+pub mod reorg;
+pub mod script;
+pub mod statestore;
 pub mod transaction;
+pub mod timedata;
+pub mod validation;
 pub mod accumulator;
 
 use clap::clap_app;
 use crossbeam::channel;
-use log::{error, info};
+use log::{error, info, warn};
 use api::Server as ApiServer;
-use network::{server, worker};
+use network::{addrman, banman, connector, ratelimit, secure, server, worker};
 use std::net;
 use std::process;
-use std::thread;
-use std::time;
 use std::sync::{Arc, Mutex};
 use crate::crypto::key_pair;
 use ring::signature::KeyPair;
-use crate::crypto::hash::{H256, H160};
-use ring::digest;
+use crate::crypto::hash::H160;
 use std::fs::OpenOptions;
 use std::io::Write;
 use std::fs::File;
@@ -42,7 +46,19 @@ fn main() {
      (@arg peer_addr: --p2p [ADDR] default_value("127.0.0.1:6000") "Sets the IP address and the port of the P2P server")
      (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
      (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
+     (@arg outgoing_peers: --("outgoing-peers") [INT] default_value("8") "Sets the number of outgoing connections to keep open")
+     (@arg ban_duration: --("ban-duration") [SECS] default_value("86400") "Sets how long misbehaving peers are banned, in seconds")
+     (@arg max_frame_size: --("max-frame-size") [BYTES] default_value("33554432") "Sets the largest message accepted from a peer, in bytes")
+     (@arg peer_bytes_per_sec: --("peer-bytes-per-sec") [BYTES] default_value("16777216") "Sets how many bytes per second a peer may send before it is disconnected")
+     (@arg peer_msgs_per_sec: --("peer-msgs-per-sec") [INT] default_value("1000") "Sets how many messages per second a peer may send before it is disconnected")
+     (@arg secure: --secure "Authenticates peers by their node key and encrypts P2P traffic, every peer has to use it too")
      (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg block_interval: --("block-interval") [MS] default_value("10000") "Sets the desired time between blocks in milliseconds")
+     (@arg retarget_interval: --("retarget-interval") [BLOCKS] default_value("10") "Sets the number of blocks between difficulty adjustments")
+     (@arg max_time_drift: --("max-time-drift") [MS] default_value("7200000") "Sets how far ahead of network-adjusted time a block timestamp may be, in milliseconds")
+     (@arg miner_threads: --("miner-threads") [INT] default_value("1") "Sets the number of threads searching for nonces")
+     (@arg block_max_size: --("block-max-size") [BYTES] default_value("1000000") "Sets the largest block the miner builds, in bytes")
+     (@arg data_dir: --("data-dir") [DIR] "Sets the directory where the blockchain is stored (defaults to data/<p2p port>)")
     )
     .get_matches();
 
@@ -70,11 +86,93 @@ fn main() {
             process::exit(1);
         });
 
+    // parse data directory
+    let data_dir = match matches.value_of("data_dir") {
+        Some(dir) => std::path::PathBuf::from(dir),
+        None => std::path::PathBuf::from("data").join(p2p_addr.port().to_string()),
+    };
+
+    // parse chain parameters
+    let block_interval = matches
+        .value_of("block_interval")
+        .unwrap()
+        .parse::<u128>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing block interval: {}", e);
+            process::exit(1);
+        });
+    let retarget_interval = matches
+        .value_of("retarget_interval")
+        .unwrap()
+        .parse::<u32>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing retarget interval: {}", e);
+            process::exit(1);
+        });
+    let max_future_drift = matches
+        .value_of("max_time_drift")
+        .unwrap()
+        .parse::<u128>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing max time drift: {}", e);
+            process::exit(1);
+        });
+    let chain_params = params::ChainParams {
+        block_interval,
+        retarget_interval,
+        max_future_drift,
+        ..params::ChainParams::default()
+    };
+
+    // parse peer limits
+    let max_frame_size = matches
+        .value_of("max_frame_size")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing max frame size: {}", e);
+            process::exit(1);
+        });
+    let bytes_per_sec = matches
+        .value_of("peer_bytes_per_sec")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing peer bytes per second: {}", e);
+            process::exit(1);
+        });
+    let messages_per_sec = matches
+        .value_of("peer_msgs_per_sec")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing peer messages per second: {}", e);
+            process::exit(1);
+        });
+    let limits = ratelimit::Limits {
+        max_frame_size,
+        bytes_per_sec,
+        messages_per_sec,
+    };
+
+    // the node key signs transactions, and authenticates the secure transport
+    let key_pkcs8 = key_pair::random_pkcs8();
+    let key_pair = key_pair::from_pkcs8(&key_pkcs8);
+    let local_public_key = key_pair.public_key().as_ref().to_vec();
+    let local_address = key_pair::address(&local_public_key);
+    let local_addr_u8: [u8; 20] = <[u8; 20]>::from(local_address);
+    println!("NODE ADDRESS GENERATED: {:?}",local_address);
+    let identity = if matches.is_present("secure") {
+        Some(Arc::new(secure::Identity::new(key_pair::from_pkcs8(&key_pkcs8))))
+    } else {
+        None
+    };
+
     // create channels between server and worker
     let (msg_tx, msg_rx) = channel::unbounded();
 
     // start the p2p server
-    let (server_ctx, server) = server::new(p2p_addr, msg_tx).unwrap();
+    let (server_ctx, server) = server::new(p2p_addr, msg_tx, limits, identity).unwrap();
     server_ctx.start().unwrap();
 
 
@@ -89,13 +187,11 @@ fn main() {
             process::exit(1);
         });
 
-    let key_pair = key_pair::random();
-    let local_public_key = key_pair.public_key().as_ref().to_vec();
-    let local_address = <H160>::from(<H256>::from(digest::digest(&digest::SHA256, &local_public_key[..])));
-    let local_addr_u8: [u8; 20] = <[u8; 20]>::from(local_address);
-    println!("NODE ADDRESS GENERATED: {:?}",local_address);
         //create new blockchain
-    let mut new_blockchain = blockchain::Blockchain::new();
+    let mut new_blockchain = blockchain::Blockchain::new(&data_dir, chain_params).unwrap_or_else(|e| {
+        error!("Error opening blockchain in {:?}: {}", data_dir, e);
+        process::exit(1);
+    });
     let blockchain = Arc::new(Mutex::new(new_blockchain));
     let mut new_orphanbuffer = worker::OrphanBuffer::new();
     let orphanbuffer = Arc::new(Mutex::new(new_orphanbuffer));
@@ -105,6 +201,23 @@ fn main() {
     let stateWitness = Arc::new(Mutex::new(new_StateWitness));
     let mut new_Accumulator = accumulator::Accumulator::new();
     let accumulator = Arc::new(Mutex::new(new_Accumulator));
+    // addresses of peers learned from the network survive restarts in the data directory
+    let addrman = Arc::new(Mutex::new(addrman::AddrManager::new(data_dir.join("peers.txt"), p2p_addr)));
+    let ban_duration = matches
+        .value_of("ban_duration")
+        .unwrap()
+        .parse::<u128>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing ban duration: {}", e);
+            process::exit(1);
+        });
+    let ban_duration = ban_duration.checked_mul(1000).unwrap_or_else(|| {
+        error!("Ban duration too long: {}", ban_duration);
+        process::exit(1);
+    });
+    let banman = Arc::new(Mutex::new(banman::BanManager::new(ban_duration)));
+    // peers may shift our clock by at most 70 minutes
+    let timedata = Arc::new(Mutex::new(timedata::TimeData::new(70 * 60 * 1000)));
     //let mut new_StateSet = transaction::StateSet::new();
     //let stateSet = Arc::new(Mutex::new(new_StateSet));
     //TODO: Add ICO
@@ -120,6 +233,48 @@ fn main() {
     if data_len == 1 {
         ifArchival = true;
     }
+
+    // pick the state up where the last run left it, and catch up with the blocks stored after it was saved
+    {
+        let mut blockchain = blockchain.lock().unwrap();
+        let mut mempool = mempool.lock().unwrap();
+        let mut stateWitness = stateWitness.lock().unwrap();
+        let mut accumulator = accumulator.lock().unwrap();
+        let restored = match statestore::load(&data_dir) {
+            Ok(Some(saved)) if blockchain.Blocks.contains_key(&saved.tip) => {
+                *stateWitness = saved.stateWitness;
+                if let Some(saved_accumulator) = saved.accumulator {
+                    *accumulator = saved_accumulator;
+                }
+                let archival_accumulator = if ifArchival { Some(&mut *accumulator) } else { None };
+                reorg::update_tip(&mut blockchain, &saved.tip, &mut mempool, &mut stateWitness, archival_accumulator);
+                info!("Restored the state at {:?}", saved.tip);
+                true
+            }
+            Ok(Some(saved)) => {
+                warn!("Saved state is at unknown block {:?}, ignoring it", saved.tip);
+                false
+            }
+            Ok(None) => false,
+            Err(e) => {
+                error!("Error loading the state in {:?}: {}", data_dir, e);
+                false
+            }
+        };
+        // the archival node can rebuild its accumulator from the stored main chain, although the outputs of an
+        // earlier ICO are lost; stateless nodes wait for the archival node to send its witnesses
+        if !restored && ifArchival && blockchain.tip.1 > 0 {
+            warn!("No saved state, rebuilding it from {} stored blocks", blockchain.tip.1 + 1);
+            for hash in blockchain.all_blocks_in_longest_chain() {
+                accumulator.connect_block(&blockchain.Blocks.get(&hash).unwrap().0);
+            }
+            accumulator.update_parameters();
+            accumulator.update_state_witness(&mut stateWitness, blockchain.tip());
+            if let Err(e) = statestore::save(&data_dir, &blockchain.tip(), &stateWitness, Some(&accumulator)) {
+                error!("Error saving the state in {:?}: {}", data_dir, e);
+            }
+        }
+    }
     // file.write_all("/n".as_bytes()).expect("write failed");
     // let mut new_sum_delay:f32 = 0.0;
     // let sum_delay = Arc::new(Mutex::new(new_sum_delay));
@@ -142,6 +297,10 @@ fn main() {
         msg_rx,
         &server,
         &accumulator,
+        &timedata,
+        &addrman,
+        &banman,
+        p2p_addr.port(),
         ifArchival,
     );
     worker_ctx.start();
@@ -162,6 +321,22 @@ fn main() {
 
 
     // start the miner
+    let block_max_size = matches
+        .value_of("block_max_size")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing block max size: {}", e);
+            process::exit(1);
+        });
+    let miner_threads = matches
+        .value_of("miner_threads")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing miner threads: {}", e);
+            process::exit(1);
+        });
     let (miner_ctx, miner) = miner::new(
         &server,
         &mempool,
@@ -170,42 +345,45 @@ fn main() {
         &blockchain,
         &local_public_key[..],
         &local_address,
+        &timedata,
+        block_max_size,
+        miner_threads,
         ifArchival,
     );
     miner_ctx.start();
 
-    // connect to known peers
-    if let Some(known_peers) = matches.values_of("known_peer") {
-        let known_peers: Vec<String> = known_peers.map(|x| x.to_owned()).collect();
-        let server = server.clone();
-        thread::spawn(move || {
-            for peer in known_peers {
-                loop {
-                    let addr = match peer.parse::<net::SocketAddr>() {
-                        Ok(x) => x,
-                        Err(e) => {
-                            error!("Error parsing peer address {}: {}", &peer, e);
-                            break;
-                        }
-                    };
-                    match server.connect(addr) {
-                        Ok(_) => {
-                            info!("Connected to outgoing peer {}", &addr);
-                            break;
-                        }
-                        Err(e) => {
-                            error!(
-                                "Error connecting to peer {}, retrying in one second: {}",
-                                addr, e
-                            );
-                            thread::sleep(time::Duration::from_millis(1000));
-                            continue;
-                        }
-                    }
-                }
-            }
+    // keep outgoing connections to known peers and to peers learned from the network
+    let known_peers: Vec<net::SocketAddr> = match matches.values_of("known_peer") {
+        Some(known_peers) => known_peers
+            .map(|peer| {
+                peer.parse::<net::SocketAddr>().unwrap_or_else(|e| {
+                    error!("Error parsing peer address {}: {}", peer, e);
+                    process::exit(1);
+                })
+            })
+            .collect(),
+        None => vec![],
+    };
+    let outgoing_peers = matches
+        .value_of("outgoing_peers")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing outgoing peers: {}", e);
+            process::exit(1);
         });
-    }
+    let connector_ctx = connector::new(
+        &server,
+        &addrman,
+        &banman,
+        &blockchain,
+        &timedata,
+        known_peers,
+        outgoing_peers,
+        p2p_addr.port(),
+        ifArchival,
+    );
+    connector_ctx.start();
 
 
     // start the API server
@@ -214,6 +392,7 @@ fn main() {
         &miner,
         &server,
         &transaction,
+        &banman,
     );
 
     loop {
@@ -1,12 +1,17 @@
+use crate::network::compact::CompactBlock;
 use crate::network::message::{Message};
 use crate::network::server::Handle as ServerHandle;
 use std::sync::{Arc, Mutex};
 
 use crate::crypto::hash::{H256, Hashable, H160};
 use crate::blockchain::Blockchain;
+use crate::reorg;
+use crate::validation;
+use crate::timedata::TimeData;
 use crate::block::{Block,Header,Content};
 use crate::crypto::merkle::{MerkleTree};
-use crate::transaction::{Transaction, Mempool, SignedTransaction, StateWitness};
+use crate::transaction::{self, Transaction, Mempool, SignedTransaction, StateWitness};
+use crate::params::block_subsidy;
 use rand::{thread_rng, Rng};
 use ring::{digest};
 
@@ -14,13 +19,23 @@ use log::{info,debug};
 
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
 use std::{time, fs};
-use std::time::{SystemTime, UNIX_EPOCH};
 
 use std::thread;
+use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use ring::signature::Ed25519KeyPair;
+use serde::Serialize;
+
+// hashes a search thread tries between two checks of the tip
+const CHECK_INTERVAL: u64 = 1 << 12;
+// longest a search runs on the same template
+const SEARCH_ROUND: Duration = Duration::from_secs(1);
 
 enum ControlSignal {
     Start(u64), // the number controls the lambda of interval between block generation
+    Pause,
+    Resume,
+    Mine(u64), // mine this many blocks, even without transactions, then pause
     Exit,
 }
 
@@ -40,7 +55,15 @@ pub struct Context {
     blockchain: Arc<Mutex<Blockchain>>,
     control_chan: Receiver<ControlSignal>,
     operating_state: OperatingState,
+    /// Lambda of the last start, used when resuming
+    lambda: u64,
+    /// Blocks left to mine before pausing, if the miner was asked for a number of blocks
+    blocks_left: Option<u64>,
     server: ServerHandle,
+    timedata: Arc<Mutex<TimeData>>,
+    block_max_size: usize,
+    num_threads: usize,
+    stats: Arc<Mutex<MinerStats>>,
     ifArchival: bool,
 }
 
@@ -48,6 +71,21 @@ pub struct Context {
 pub struct Handle {
     /// Channel for sending signal to the miner thread
     control_chan: Sender<ControlSignal>,
+    stats: Arc<Mutex<MinerStats>>,
+}
+
+/// Counters of the nonce search, exposed through the API
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct MinerStats {
+    pub threads: usize,
+    /// Hashes tried since the miner started
+    pub hashes: u64,
+    /// Time spent searching, in milliseconds
+    pub mining_time_ms: u64,
+    /// Hashes per second during the last search
+    pub hash_rate: f64,
+    /// Blocks mined and accepted into the local chain
+    pub blocks: u64,
 }
 
 pub fn new(
@@ -58,9 +96,14 @@ pub fn new(
     blockchain: &Arc<Mutex<Blockchain>>,
     local_public_key: &[u8],
     local_address: &H160,
+    timedata: &Arc<Mutex<TimeData>>,
+    block_max_size: usize,
+    num_threads: usize,
     ifArchival: bool,
 ) -> (Context, Handle) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
+    let num_threads = std::cmp::max(num_threads, 1);
+    let stats = Arc::new(Mutex::new(MinerStats { threads: num_threads, ..MinerStats::default() }));
 
     let ctx = Context {
         local_address: *local_address,
@@ -71,17 +114,63 @@ pub fn new(
         blockchain: Arc::clone(blockchain),
         control_chan: signal_chan_receiver,
         operating_state: OperatingState::Paused,
+        lambda: 0,
+        blocks_left: None,
         server: server.clone(),
+        timedata: Arc::clone(timedata),
+        block_max_size: block_max_size,
+        num_threads: num_threads,
+        stats: Arc::clone(&stats),
         ifArchival: ifArchival,
     };
 
     let handle = Handle {
         control_chan: signal_chan_sender,
+        stats: stats,
     };
 
     (ctx, handle)
 }
 
+/// Pick valid mempool transactions for a child of `parent` by descending fee rate (fee per serialized byte),
+/// as long as the block, including its header and coinbase, stays within `max_size` bytes and the consensus
+/// limit. Returns the transactions and the fees they pay.
+pub fn block_template(mempool: &Mempool, blockchain: &Blockchain, stateWitness: &StateWitness, parent: &H256, max_size: usize) -> (Vec<SignedTransaction>, u64) {
+    let max_size = std::cmp::min(max_size, blockchain.params.max_block_size);
+    let mut candidates = Vec::<(u64, u64, &SignedTransaction)>::new();
+    for tx in mempool.Transactions.values() {
+        if validation::check_transaction(tx, blockchain, stateWitness, parent).is_err() {
+            continue;
+        }
+        let fee = validation::transaction_fee(&tx.transaction, stateWitness).unwrap().unwrap();
+        let size = bincode::serialized_size(tx).unwrap();
+        candidates.push((fee, size, tx));
+    }
+    // compare fee rates without dividing: fee_a / size_a < fee_b / size_b exactly when fee_a * size_b < fee_b * size_a
+    candidates.sort_by(|a, b| (b.0 as u128 * a.1 as u128).cmp(&(a.0 as u128 * b.1 as u128)));
+
+    // a block without transactions but the coinbase; each transaction adds exactly its own size
+    let empty = Block {
+        Header: Header { parent: *parent, nonce: 0, difficulty: H256::default(), timestamp: 0, merkleRoot: H256::default() },
+        Content: Content { content: vec![transaction::coinbase(0, &H160::default(), 0)] },
+    };
+    let mut size = bincode::serialized_size(&empty).unwrap();
+    let mut selected = Vec::<SignedTransaction>::new();
+    let mut fees: u64 = 0;
+    for (fee, tx_size, tx) in candidates {
+        if size + tx_size > max_size as u64 {
+            continue;
+        }
+        fees = match fees.checked_add(fee) {
+            Some(fees) => fees,
+            None => continue,
+        };
+        size += tx_size;
+        selected.push(tx.clone());
+    }
+    (selected, fees)
+}
+
 impl Handle {
     pub fn exit(&self) {
         self.control_chan.send(ControlSignal::Exit).unwrap();
@@ -93,6 +182,28 @@ impl Handle {
             .unwrap();
     }
 
+    pub fn pause(&self) {
+        self.control_chan.send(ControlSignal::Pause).unwrap();
+    }
+
+    /// Continue with the lambda of the last start and, if the miner was asked for a number of blocks, the blocks left
+    pub fn resume(&self) {
+        self.control_chan.send(ControlSignal::Resume).unwrap();
+    }
+
+    /// Mine exactly `blocks` blocks, then pause
+    pub fn mine(&self, blocks: u64) {
+        self.control_chan.send(ControlSignal::Mine(blocks)).unwrap();
+    }
+
+    pub fn step(&self) {
+        self.mine(1);
+    }
+
+    pub fn stats(&self) -> MinerStats {
+        self.stats.lock().unwrap().clone()
+    }
+
 }
 
 impl Context {
@@ -115,6 +226,25 @@ impl Context {
             ControlSignal::Start(i) => {
                 info!("Miner starting in continuous mode with lambda {}", i);
                 self.operating_state = OperatingState::Run(i);
+                self.lambda = i;
+                self.blocks_left = None;
+            }
+            ControlSignal::Pause => {
+                info!("Miner paused");
+                self.operating_state = OperatingState::Paused;
+            }
+            ControlSignal::Resume => {
+                info!("Miner resuming with lambda {}", self.lambda);
+                self.operating_state = OperatingState::Run(self.lambda);
+            }
+            ControlSignal::Mine(0) => {
+                self.operating_state = OperatingState::Paused;
+                self.blocks_left = None;
+            }
+            ControlSignal::Mine(blocks) => {
+                info!("Miner mining {} blocks with lambda {}", blocks, self.lambda);
+                self.operating_state = OperatingState::Run(self.lambda);
+                self.blocks_left = Some(blocks);
             }
         }
     }
@@ -174,52 +304,28 @@ impl Context {
 
             // TODO: actual mining
 
-            if self.mempool.lock().unwrap().Transactions.keys().len() > 0 && !self.ifArchival {
+            let mut searched = false;
+            if (self.mempool.lock().unwrap().Transactions.keys().len() > 0 || self.blocks_left.is_some()) && !self.ifArchival {
                 //info!("MINER: STARTING...");
-                let nonce:u32 = thread_rng().gen();
-                let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_millis();
-
-                // difficulty
-                let mut bytes32 = [255u8;32];
-                bytes32[0]=1;
-                bytes32[1]=1;
-                let difficulty : H256 = bytes32.into();
-
-                // read transactions from mempool
-                let mut signedTransaction = Vec::<SignedTransaction>::new();
-                let block_size_limit = 5;
-                let mut tx_counter = 0;
-
-                //let mut state = self.state.lock().unwrap();
-                let mut stateWitness = self.stateWitness.lock().unwrap();
-                let mut mempool = self.mempool.lock().unwrap();
-
-                let mut key_iter= mempool.Transactions.keys();
-                for key in mempool.Transactions.keys(){
-                    //println!("MINER: MEMPOOL KEYS:{:?}, INPUT: {:?}, OUTPUT: {:?}", key, mempool.Transactions.get(key).unwrap().transaction.Input, mempool.Transactions.get(key).unwrap().transaction.Output);
-                }
-                while tx_counter < block_size_limit {
-                    match key_iter.next() {
-                        Some(hash) => {
-                            //println!("Miner: tx: {:?}",hash);
-                            //println!("Miner: preTx: {:?}, PreIndex: {:?}",mempool.getPreTxHash(hash), mempool.getPreIndex(hash));
-                            //double spent check and verify signature
-                            if stateWitness.ifNotDoubleSpent(&mempool.Transactions.get(&hash).unwrap().transaction.Input,&self.blockchain.lock().unwrap().tip.0 )// // NEW TODO Change it to state witness
-                                && mempool.Transactions.get(hash).unwrap().verifySignedTransaction() {
-                                //info!("Miner: Adding to block HERE");
-                                signedTransaction.push(mempool.Transactions.get(hash).unwrap().clone());
-                                tx_counter = tx_counter + 1;
-                            }
-                        }
-                        None => {
-                            break;
-                        }
-                    }
-                }
-                std::mem::drop(mempool);
+
+                // difficulty expected for a child of the current tip, and a timestamp past its median time
+                let blockchain = self.blockchain.lock().unwrap();
+                let parent = blockchain.tip();
+                let difficulty : H256 = blockchain.next_difficulty(&parent);
+                let timestamp = std::cmp::max(self.timedata.lock().unwrap().adjusted_time(), blockchain.median_time_past(&parent) + 1);
+                let height = blockchain.Blocks.get(&parent).unwrap().1 + 1;
+
+                // fill the block by fee rate; the coinbase pays the subsidy and the collected fees to us
+                let mempool = self.mempool.lock().unwrap();
+                let stateWitness = self.stateWitness.lock().unwrap();
+                let (mut signedTransaction, fees) = block_template(&mempool, &blockchain, &stateWitness, &parent, self.block_max_size);
                 std::mem::drop(stateWitness);
+                std::mem::drop(mempool);
+                let reward = block_subsidy(height, &blockchain.params).saturating_add(fees);
+                std::mem::drop(blockchain);
 
-                if signedTransaction.capacity() > 0 {
+                if !signedTransaction.is_empty() || self.blocks_left.is_some() {
+                    signedTransaction.insert(0, transaction::coinbase(height, &self.local_address, reward));
                     //info!("MINER: ADDING...");
 
                     //info!("MINER: MERKLETREE CHECKING...");
@@ -230,8 +336,8 @@ impl Context {
                     };
 
                     let newHeader = Header{
-                        parent: self.blockchain.lock().unwrap().tip(),
-                        nonce:  nonce,
+                        parent: parent,
+                        nonce:  0,
                         difficulty: difficulty,
                         timestamp:  timestamp,
                         merkleRoot: MerkleTree.root(),
@@ -245,45 +351,52 @@ impl Context {
                     //println!("2: {:?}", difficulty );
                     //info!("MINER: BLOCK CREATED");
 
-                    if newBlock.hash() <= difficulty {
-
-                        let mut contents = newBlock.Content.content.clone();
-                        //let mut state = self.state.lock().unwrap();
-                        let mut stateWitness = self.stateWitness.lock().unwrap();
-                        let mut mempool = self.mempool.lock().unwrap();
-                        //let mut stateSet = self.stateSet.lock().unwrap();
-                        let mut check = true;
-                        for content in contents.iter(){
-                            if  stateWitness.ifNotDoubleSpent(&content.transaction.Input,&self.blockchain.lock().unwrap().tip.0 )
-                                && content.verifySignedTransaction() {//state.ifNotDoubleSpent(content)
-                                check = check && true;
-                            }
-                            else{
-                                check = check && false;
-                                break;
-                            }
-                        }
+                    searched = true;
+                    if let Some(newBlock) = self.search_nonce(&newBlock) {
+
+                        let now = self.timedata.lock().unwrap().adjusted_time();
+                        let blockchain = self.blockchain.lock().unwrap();
+                        let stateWitness = self.stateWitness.lock().unwrap();
+                        let result = validation::check_block(&newBlock, &blockchain, &stateWitness, self.ifArchival, now);
                         std::mem::drop(stateWitness);
-                        std::mem::drop(mempool);
-                        if check {
+                        std::mem::drop(blockchain);
+                        if let Err(e) = &result {
+                            info!("MINER: DISCARDING MINED BLOCK {:?}: {}", newBlock.hash(), e);
+                        }
+                        if result.is_ok() {
                             let mut blockchain = self.blockchain.lock().unwrap();
 
+                            let old_tip = blockchain.tip();
                             let tip_hash = blockchain.insert(&newBlock);
 
 
                             //info!("MINER: NEW BLOCK ADDED");
                             miner_counter += 1;
+                            self.stats.lock().unwrap().blocks += 1;
+                            if let Some(left) = self.blocks_left {
+                                if left <= 1 {
+                                    info!("Miner mined the requested blocks, pausing");
+                                    self.blocks_left = None;
+                                    self.operating_state = OperatingState::Paused;
+                                } else {
+                                    self.blocks_left = Some(left - 1);
+                                }
+                            }
                             println!("MINER: CURRENT MINER COUNT: {:?}", miner_counter);
                             println!("MINER: CURRENT BLOCKCHAIN HEIGHT: {:?}", blockchain.tip.1);
 
                             //let mut state = self.state.lock().unwrap();
                             //let mut stateWitness = self.stateWitness.lock().unwrap();
                             let mut mempool = self.mempool.lock().unwrap();
-                            mempool.updateMempool(&contents);
+                            let mut stateWitness = self.stateWitness.lock().unwrap();
+                            reorg::update_tip(&mut blockchain, &old_tip, &mut mempool, &mut stateWitness, None);
+                            std::mem::drop(stateWitness);
                             /*for key in state.Outputs.keys() {
                                 println!("MINER: RECP: {:?}, VALUE {:?}", state.Outputs.get(key).unwrap().1, state.Outputs.get(key).unwrap().0);
                             }*/
-                            self.server.broadcast(Message::NewBlockHashes(blockchain.all_blocks_in_longest_chain()));
+                            if tip_hash == newBlock.hash() {
+                                self.server.broadcast(Message::CompactBlock(CompactBlock::new(&newBlock)));
+                            }
                             //info!("MINER: BLOCK MESSAGES SENT");
                             std::mem::drop(blockchain);
                             std::mem::drop(mempool);
@@ -300,8 +413,121 @@ impl Context {
                     thread::sleep(interval);
                 }
             }
-            let interval = time::Duration::from_micros(1000 as u64);
-            thread::sleep(interval);
+            // nothing to mine yet, wait for transactions
+            if !searched {
+                let interval = time::Duration::from_micros(1000 as u64);
+                thread::sleep(interval);
+            }
         }
     }
+
+    /// Search the nonce space of `template` on `num_threads` threads. Thread `i` tries the nonces congruent to
+    /// `i` modulo the number of threads, starting from a random offset. The search gives up when another block
+    /// extends the tip, and after `SEARCH_ROUND` so that new transactions are picked up, and as soon as a control
+    /// signal arrives. A round is far too short to exhaust the nonces; the next one starts from a new template,
+    /// with the current time as its timestamp.
+    fn search_nonce(&self, template: &Block) -> Option<Block> {
+        let threads = self.num_threads as u32;
+        let start_nonce: u32 = thread_rng().gen();
+        let started = Instant::now();
+        let found = Mutex::new(None::<Block>);
+        let stop = AtomicBool::new(false);
+        let hashes = AtomicU64::new(0);
+        let blockchain = &self.blockchain;
+        let control_chan = &self.control_chan;
+        crossbeam::thread::scope(|s| {
+            for i in 0..threads {
+                let (found, stop, hashes) = (&found, &stop, &hashes);
+                s.spawn(move |_| {
+                    let mut block = template.clone();
+                    let mut nonce = start_nonce.wrapping_add(i);
+                    let mut tried: u64 = 0;
+                    loop {
+                        block.Header.nonce = nonce;
+                        tried += 1;
+                        if block.hash() <= block.getdifficulty() {
+                            *found.lock().unwrap() = Some(block);
+                            stop.store(true, Ordering::SeqCst);
+                            break;
+                        }
+                        if tried % CHECK_INTERVAL == 0 {
+                            if stop.load(Ordering::SeqCst) {
+                                break;
+                            }
+                            if started.elapsed() > SEARCH_ROUND || !control_chan.is_empty() || blockchain.lock().unwrap().tip() != block.getparent() {
+                                stop.store(true, Ordering::SeqCst);
+                                break;
+                            }
+                        }
+                        nonce = nonce.wrapping_add(threads);
+                    }
+                    hashes.fetch_add(tried, Ordering::SeqCst);
+                });
+            }
+        }).unwrap();
+
+        let hashes = hashes.load(Ordering::SeqCst);
+        let elapsed = started.elapsed();
+        let mut stats = self.stats.lock().unwrap();
+        stats.hashes += hashes;
+        stats.mining_time_ms += elapsed.as_millis() as u64;
+        stats.hash_rate = hashes as f64 / elapsed.as_secs_f64().max(1e-9);
+        std::mem::drop(stats);
+        found.into_inner().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::tests::temp_data_dir;
+    use crate::crypto::hash::tests::generate_random_hash;
+    use crate::crypto::key_pair;
+    use crate::params::{ChainParams, COIN};
+    use crate::script::Script;
+    use crate::transaction::{generate_transaction, sign, witness};
+    use ring::signature::KeyPair;
+
+    #[test]
+    fn template_by_fee_rate() {
+        let dir = temp_data_dir("template");
+        let blockchain = Blockchain::new(&dir, ChainParams::default()).unwrap();
+        let parent = blockchain.tip();
+        let owner: H160 = generate_random_hash().into();
+
+        // three outputs of 10 coins with witnesses against the accumulator proof 30 of the tip
+        let mut stateWitness = StateWitness::new();
+        stateWitness.AccumulatorProof.insert(parent, 30);
+        let mut mempool = Mempool::new();
+        let mut spend = |prime: u32, value: u64| {
+            let prev = generate_random_hash();
+            let key = key_pair::random();
+            stateWitness.addStates(prev, 0, 10 * COIN, Script::PayToPubkeyHash(key_pair::address(key.public_key().as_ref())), prime, 30 / prime as u128);
+            let w = witness { prime_number: prime, witness: 30 / prime as u128 };
+            let transaction = generate_transaction(&vec![prev], &vec![0], &vec![w], &vec![value], &vec![owner]);
+            let tx = SignedTransaction::new(&transaction, &sign(&transaction, &key), &key.public_key());
+            mempool.add(&tx, 0).unwrap();
+            tx
+        };
+        let low_fee = spend(2, 99 * COIN / 10);
+        let high_fee = spend(3, 9 * COIN);
+        let overspend = spend(5, 11 * COIN);
+
+        let (selected, fees) = block_template(&mempool, &blockchain, &stateWitness, &parent, usize::max_value());
+        let selected: Vec<H256> = selected.iter().map(|tx| tx.hash()).collect();
+        assert_eq!(selected, vec![high_fee.hash(), low_fee.hash()]);
+        assert_eq!(fees, 11 * COIN / 10);
+        assert!(!selected.contains(&overspend.hash()));
+
+        // only room for one transaction next to the coinbase
+        let block = Block {
+            Header: Header { parent, nonce: 0, difficulty: H256::default(), timestamp: 0, merkleRoot: H256::default() },
+            Content: Content { content: vec![transaction::coinbase(1, &owner, 50 * COIN), high_fee.clone()] },
+        };
+        let max_size = bincode::serialized_size(&block).unwrap() as usize;
+        let (selected, _) = block_template(&mempool, &blockchain, &stateWitness, &parent, max_size);
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].hash(), high_fee.hash());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,199 @@
+use log::warn;
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+/// Most addresses sent in one `Addr` message
+pub const MAX_ADDR_PER_MESSAGE: usize = 1000;
+/// Most addresses kept, the least recently seen are forgotten first
+pub const MAX_ADDRS: usize = 10000;
+/// Failed connection attempts in a row after which an address is forgotten
+pub const MAX_FAILURES: u32 = 5;
+/// How long to wait before trying an address again, in milliseconds
+pub const RETRY_INTERVAL: u128 = 60 * 1000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AddrInfo {
+    pub last_seen: u128,            // last time we heard of or talked to the peer, in milliseconds
+    pub last_attempt: Option<u128>, // last time we tried to connect to it, in milliseconds
+    pub failures: u32,              // failed attempts since the last success
+}
+
+/// Addresses of peers learned from the network and from the command line, persisted in a peers file with one
+/// `<address> <last seen>` line per peer.
+pub struct AddrManager {
+    addrs: HashMap<SocketAddr, AddrInfo>,
+    local: SocketAddr,
+    path: PathBuf,
+    dirty: bool,
+}
+
+impl AddrManager {
+    /// Load the peers file at `path`, if any. `local` is our own listening address, which is never recorded
+    pub fn new(path: PathBuf, local: SocketAddr) -> Self {
+        let mut addrman = AddrManager {
+            addrs: HashMap::new(),
+            local,
+            path,
+            dirty: false,
+        };
+        let content = match fs::read_to_string(&addrman.path) {
+            Ok(content) => content,
+            Err(_) => return addrman,
+        };
+        for line in content.lines() {
+            let mut fields = line.split_whitespace();
+            let addr = fields.next().and_then(|addr| addr.parse::<SocketAddr>().ok());
+            let last_seen = fields.next().and_then(|time| time.parse::<u128>().ok());
+            match (addr, last_seen) {
+                (Some(addr), Some(last_seen)) => {
+                    addrman.add(addr, last_seen);
+                }
+                _ => warn!("Ignoring malformed line {:?} in {:?}", line, addrman.path),
+            }
+        }
+        addrman.dirty = false;
+        addrman
+    }
+
+    pub fn len(&self) -> usize {
+        self.addrs.len()
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<&AddrInfo> {
+        self.addrs.get(addr)
+    }
+
+    /// Record an address heard of at `time`. Returns whether it is new
+    pub fn add(&mut self, addr: SocketAddr, time: u128) -> bool {
+        if addr == self.local || addr.ip().is_unspecified() || addr.port() == 0 {
+            return false;
+        }
+        if let Some(info) = self.addrs.get_mut(&addr) {
+            if info.last_seen < time {
+                info.last_seen = time;
+                self.dirty = true;
+            }
+            return false;
+        }
+        if self.addrs.len() >= MAX_ADDRS {
+            let oldest = self.addrs.iter().min_by_key(|(addr, info)| (info.last_seen, **addr)).map(|(addr, _)| *addr);
+            if let Some(oldest) = oldest {
+                self.addrs.remove(&oldest);
+            }
+        }
+        self.addrs.insert(addr, AddrInfo { last_seen: time, last_attempt: None, failures: 0 });
+        self.dirty = true;
+        true
+    }
+
+    /// We are about to connect to `addr`
+    pub fn attempt(&mut self, addr: &SocketAddr, now: u128) {
+        if let Some(info) = self.addrs.get_mut(addr) {
+            info.last_attempt = Some(now);
+        }
+    }
+
+    /// We completed a handshake with `addr`
+    pub fn good(&mut self, addr: &SocketAddr, now: u128) {
+        self.add(*addr, now);
+        if let Some(info) = self.addrs.get_mut(addr) {
+            info.failures = 0;
+        }
+    }
+
+    /// Connecting to `addr` failed. The address is forgotten after `MAX_FAILURES` failures in a row
+    pub fn failed(&mut self, addr: &SocketAddr) {
+        let forget = match self.addrs.get_mut(addr) {
+            Some(info) => {
+                info.failures += 1;
+                info.failures >= MAX_FAILURES
+            }
+            None => false,
+        };
+        if forget {
+            self.addrs.remove(addr);
+            self.dirty = true;
+        }
+    }
+
+    /// The address to connect to next, not in `exclude` and not tried within the retry interval: the one with
+    /// the fewest failures, then the most recently seen
+    pub fn select(&self, exclude: &HashSet<SocketAddr>, now: u128) -> Option<SocketAddr> {
+        self.addrs
+            .iter()
+            .filter(|(addr, _)| !exclude.contains(*addr))
+            .filter(|(_, info)| info.last_attempt.map_or(true, |attempt| now.saturating_sub(attempt) >= RETRY_INTERVAL))
+            .min_by_key(|(addr, info)| (info.failures, std::cmp::Reverse(info.last_seen), **addr))
+            .map(|(addr, _)| *addr)
+    }
+
+    /// Up to `max` of the most recently seen addresses, to answer a `GetAddr`
+    pub fn sample(&self, max: usize) -> Vec<SocketAddr> {
+        let mut addrs: Vec<(&SocketAddr, &AddrInfo)> = self.addrs.iter().collect();
+        addrs.sort_by_key(|(addr, info)| (std::cmp::Reverse(info.last_seen), **addr));
+        addrs.into_iter().take(max).map(|(addr, _)| *addr).collect()
+    }
+
+    /// Write the peers file if an address was added or forgotten since the last save
+    pub fn save(&mut self) -> std::io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let tmp = self.path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp)?;
+        for (addr, info) in self.addrs.iter() {
+            writeln!(file, "{} {}", addr, info.last_seen)?;
+        }
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::tests::temp_data_dir;
+
+    #[test]
+    fn select_and_persist() {
+        let dir = temp_data_dir("addrman");
+        fs::create_dir_all(&dir).unwrap();
+        let local: SocketAddr = "127.0.0.1:6000".parse().unwrap();
+        let a: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let b: SocketAddr = "127.0.0.1:6002".parse().unwrap();
+        let mut addrman = AddrManager::new(dir.join("peers.txt"), local);
+
+        // our own address is never recorded
+        assert!(!addrman.add(local, 100));
+        assert!(addrman.add(a, 100));
+        assert!(addrman.add(b, 200));
+        assert!(!addrman.add(a, 50));
+        assert_eq!(addrman.sample(1), vec![b]);
+
+        // the most recently seen first, skipping connected and recently tried addresses
+        let mut exclude = HashSet::new();
+        assert_eq!(addrman.select(&exclude, 1000), Some(b));
+        exclude.insert(b);
+        assert_eq!(addrman.select(&exclude, 1000), Some(a));
+        addrman.attempt(&a, 1000);
+        assert_eq!(addrman.select(&exclude, 1000), None);
+        assert_eq!(addrman.select(&exclude, 1000 + RETRY_INTERVAL), Some(a));
+
+        // an address failing too often is forgotten
+        for _ in 0..MAX_FAILURES {
+            addrman.failed(&a);
+        }
+        assert!(addrman.get(&a).is_none());
+
+        addrman.save().unwrap();
+        let reopened = AddrManager::new(dir.join("peers.txt"), local);
+        assert_eq!(reopened.len(), 1);
+        assert_eq!(reopened.get(&b).unwrap().last_seen, 200);
+    }
+}
@@ -0,0 +1,125 @@
+use serde::Serialize;
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+
+/// Misbehavior score at which a peer is disconnected and banned
+pub const BAN_THRESHOLD: u32 = 100;
+/// How long a misbehaving peer stays banned by default, in milliseconds
+pub const DEFAULT_BAN_DURATION: u128 = 24 * 60 * 60 * 1000;
+
+// misbehavior scores: anything that cannot happen to an honest peer bans at once
+pub const UNDECODABLE_MESSAGE: u32 = 100;
+pub const INVALID_POW: u32 = 100;
+pub const BAD_SIGNATURE: u32 = 100;
+pub const INVALID_DATA: u32 = 100;
+pub const UNSOLICITED: u32 = 20;
+pub const PROTOCOL_VIOLATION: u32 = 20;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Ban {
+    pub addr: IpAddr,
+    pub port: Option<u16>, // the listening port banned, or none for the whole address
+    pub until: u128, // in milliseconds since the unix epoch
+    pub reason: String,
+}
+
+/// Addresses we refuse to talk to until their ban expires. Bans of misbehaving peers are per IP address, since a
+/// banned peer can reconnect from any port, except on loopback: local nodes all share it, and only the one
+/// listening on the offending port is banned.
+pub struct BanManager {
+    bans: HashMap<(IpAddr, Option<u16>), Ban>,
+    duration: u128,
+}
+
+impl BanManager {
+    /// `duration` is the default length of a ban, in milliseconds
+    pub fn new(duration: u128) -> Self {
+        BanManager {
+            bans: HashMap::new(),
+            duration,
+        }
+    }
+
+    /// Ban a misbehaving peer listening on `listen_addr` for the default duration, starting `now`
+    pub fn ban_peer(&mut self, listen_addr: SocketAddr, now: u128, reason: &str) {
+        let port = if listen_addr.ip().is_loopback() { Some(listen_addr.port()) } else { None };
+        self.ban(listen_addr.ip(), port, now, reason);
+    }
+
+    /// Ban `addr`, or only its listening `port`, for the default duration, starting `now`. A ban that would end
+    /// past the end of time lasts forever
+    pub fn ban(&mut self, addr: IpAddr, port: Option<u16>, now: u128, reason: &str) {
+        self.ban_until(addr, port, now.saturating_add(self.duration), reason);
+    }
+
+    /// Ban `addr`, or only its listening `port`, until `until`, replacing any ban it already has
+    pub fn ban_until(&mut self, addr: IpAddr, port: Option<u16>, until: u128, reason: &str) {
+        self.bans.insert((addr, port), Ban { addr, port, until, reason: reason.to_string() });
+    }
+
+    /// Lift the ban of `addr`, or of its listening `port`. Returns whether it was banned
+    pub fn unban(&mut self, addr: &IpAddr, port: Option<u16>) -> bool {
+        self.bans.remove(&(*addr, port)).is_some()
+    }
+
+    /// Whether `addr` is banned, as a whole or on its port
+    pub fn is_banned(&self, addr: &SocketAddr, now: u128) -> bool {
+        [None, Some(addr.port())]
+            .iter()
+            .filter_map(|port| self.bans.get(&(addr.ip(), *port)))
+            .any(|ban| ban.until > now)
+    }
+
+    /// The bans still in force at `now`, forgetting the expired ones
+    pub fn list(&mut self, now: u128) -> Vec<Ban> {
+        self.bans.retain(|_, ban| ban.until > now);
+        let mut bans: Vec<Ban> = self.bans.values().cloned().collect();
+        bans.sort_by_key(|ban| (ban.addr, ban.port));
+        bans
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ban_expire_unban() {
+        let mut banman = BanManager::new(1000);
+        let a: SocketAddr = "10.0.0.1:6000".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+        banman.ban_peer(a, 0, "invalid block");
+        banman.ban_until(b, None, 5000, "by hand");
+        assert!(banman.is_banned(&a, 999));
+        assert!(!banman.is_banned(&a, 1000));
+        assert_eq!(banman.list(0).len(), 2);
+        // expired bans are dropped from the list
+        assert_eq!(banman.list(1000), vec![Ban { addr: b, port: None, until: 5000, reason: "by hand".to_string() }]);
+        assert!(banman.unban(&b, None));
+        assert!(!banman.unban(&b, None));
+        assert!(!banman.is_banned(&SocketAddr::new(b, 6000), 1000));
+        // a default duration too long to add up does not wrap around
+        let mut banman = BanManager::new(u128::MAX);
+        banman.ban_peer(a, 1000, "invalid block");
+        assert!(banman.is_banned(&a, u128::MAX - 1));
+    }
+
+    #[test]
+    fn ban_one_of_two_peers_on_one_address() {
+        let mut banman = BanManager::new(1000);
+        // a remote host is banned whatever port it uses
+        let offender: SocketAddr = "10.0.0.1:6000".parse().unwrap();
+        banman.ban_peer(offender, 0, "invalid block");
+        assert!(banman.is_banned(&"10.0.0.1:6001".parse().unwrap(), 0));
+        // local nodes share the loopback address, only the offender is banned
+        let offender: SocketAddr = "127.0.0.1:6000".parse().unwrap();
+        let neighbour: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        banman.ban_peer(offender, 0, "invalid block");
+        assert!(banman.is_banned(&offender, 0));
+        assert!(!banman.is_banned(&neighbour, 0));
+        // unless the whole address is banned by hand
+        banman.ban(offender.ip(), None, 0, "by hand");
+        assert!(banman.is_banned(&neighbour, 0));
+    }
+}
@@ -0,0 +1,178 @@
+use crate::block::{Block, Content, Header};
+use crate::crypto::hash::{Hashable, H256};
+use crate::crypto::merkle::MerkleTree;
+use crate::transaction::{Mempool, SignedTransaction};
+use rand::Rng;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+
+/// Bytes of a short transaction id
+const SHORT_ID_SIZE: usize = 6;
+
+/// A block announced as its header and short ids of its transactions, which the receiver looks up in its own
+/// mempool. The coinbase, which no mempool holds, always travels in full.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CompactBlock {
+    pub header: Header,
+    /// Mixed into the short ids, so that a peer cannot make collisions ahead of time
+    pub salt: u64,
+    /// Short ids of the transactions not prefilled, in block order
+    pub short_ids: Vec<u64>,
+    /// Transactions sent in full, with their index in the block, in increasing order
+    pub prefilled: Vec<(u32, SignedTransaction)>,
+}
+
+/// The short id of a transaction in the block with hash `block_hash`
+pub fn short_id(block_hash: &H256, salt: u64, tx_hash: &H256) -> u64 {
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    ctx.update(block_hash.as_ref());
+    ctx.update(&salt.to_le_bytes());
+    ctx.update(tx_hash.as_ref());
+    let hash = ctx.finish();
+    let mut bytes = [0u8; 8];
+    bytes[..SHORT_ID_SIZE].copy_from_slice(&hash.as_ref()[..SHORT_ID_SIZE]);
+    u64::from_le_bytes(bytes)
+}
+
+impl CompactBlock {
+    pub fn new(block: &Block) -> Self {
+        let hash = block.hash();
+        let salt: u64 = rand::thread_rng().gen();
+        let mut short_ids = Vec::new();
+        let mut prefilled = Vec::new();
+        for (index, tx) in block.Content.content.iter().enumerate() {
+            if index == 0 {
+                prefilled.push((0, tx.clone()));
+            } else {
+                short_ids.push(short_id(&hash, salt, &tx.hash()));
+            }
+        }
+        CompactBlock { header: block.Header.clone(), salt, short_ids, prefilled }
+    }
+
+    pub fn hash(&self) -> H256 {
+        self.header.hash()
+    }
+}
+
+/// A block being rebuilt from a compact block, waiting for the transactions the mempool did not have
+#[derive(Debug, Clone)]
+pub struct PartialBlock {
+    header: Header,
+    slots: Vec<Option<SignedTransaction>>,
+}
+
+impl PartialBlock {
+    /// Fill in what the compact block carries and what the mempool holds. Fails if the compact block is malformed
+    pub fn new(compact: &CompactBlock, mempool: &Mempool) -> Result<Self, String> {
+        let count = compact.short_ids.len() + compact.prefilled.len();
+        let mut slots: Vec<Option<SignedTransaction>> = vec![None; count];
+        let mut last = None;
+        for (index, tx) in compact.prefilled.iter() {
+            if last.map_or(false, |last| *index <= last) || *index as usize >= count {
+                return Err(format!("prefilled transaction index {} out of order or range", index));
+            }
+            last = Some(*index);
+            slots[*index as usize] = Some(tx.clone());
+        }
+        // the slots left empty by the prefilled transactions, in block order
+        let open: Vec<usize> = (0..count).filter(|index| slots[*index].is_none()).collect();
+        let mut positions = HashMap::<u64, usize>::new();
+        for (short_id, index) in compact.short_ids.iter().zip(open.iter()) {
+            if positions.insert(*short_id, *index).is_some() {
+                return Err(format!("duplicate short id {:x}", short_id));
+            }
+        }
+        // a short id matching several mempool transactions is left for the peer to send
+        let hash = compact.hash();
+        let mut matches = HashMap::<usize, Option<&SignedTransaction>>::new();
+        for (tx_hash, tx) in mempool.Transactions.iter() {
+            if let Some(index) = positions.get(&short_id(&hash, compact.salt, tx_hash)) {
+                matches.entry(*index).and_modify(|found| *found = None).or_insert(Some(tx));
+            }
+        }
+        for (index, tx) in matches {
+            slots[index] = tx.cloned();
+        }
+        Ok(PartialBlock { header: compact.header.clone(), slots })
+    }
+
+    /// Indexes of the transactions still missing
+    pub fn missing(&self) -> Vec<u32> {
+        (0..self.slots.len()).filter(|index| self.slots[*index].is_none()).map(|index| index as u32).collect()
+    }
+
+    /// Fill the missing transactions, in the order `missing` lists them
+    pub fn fill(&mut self, txs: Vec<SignedTransaction>) -> Result<(), String> {
+        let missing = self.missing();
+        if txs.len() != missing.len() {
+            return Err(format!("expected {} transactions, got {}", missing.len(), txs.len()));
+        }
+        for (index, tx) in missing.into_iter().zip(txs) {
+            self.slots[index as usize] = Some(tx);
+        }
+        Ok(())
+    }
+
+    /// The block, once every transaction is known and they match the merkle root of the header. A mismatch
+    /// means a short id collision, or a peer lying about the transactions
+    pub fn block(&self) -> Option<Block> {
+        let content: Vec<SignedTransaction> = self.slots.iter().cloned().collect::<Option<Vec<_>>>()?;
+        if MerkleTree::new(&content).root() != self.header.merkleRoot {
+            return None;
+        }
+        Some(Block { Header: self.header.clone(), Content: Content { content } })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::generate_random_block_;
+    use crate::crypto::hash::tests::generate_random_hash;
+    use crate::transaction::generate_random_signed_transaction_;
+
+    fn block_with(txs: usize) -> Block {
+        let mut block = generate_random_block_(&generate_random_hash());
+        for _ in 0..txs {
+            block.Content.content.push(generate_random_signed_transaction_());
+        }
+        block.Header.merkleRoot = MerkleTree::new(&block.Content.content).root();
+        block
+    }
+
+    #[test]
+    fn reconstruct_from_mempool() {
+        let block = block_with(3);
+        let compact = CompactBlock::new(&block);
+        assert_eq!(compact.prefilled.len(), 1);
+        assert_eq!(compact.short_ids.len(), 3);
+
+        // the mempool holds all but the second transaction
+        let mut mempool = Mempool::new();
+        mempool.add(&block.Content.content[1], 0).unwrap();
+        mempool.add(&block.Content.content[3], 0).unwrap();
+        mempool.add(&generate_random_signed_transaction_(), 0).unwrap();
+        let mut partial = PartialBlock::new(&compact, &mempool).unwrap();
+        assert_eq!(partial.missing(), vec![2]);
+        assert!(partial.block().is_none());
+        assert!(partial.fill(vec![]).is_err());
+        partial.fill(vec![block.Content.content[2].clone()]).unwrap();
+        assert_eq!(partial.block().unwrap().hash(), block.hash());
+
+        // a wrong transaction does not match the merkle root
+        let mut partial = PartialBlock::new(&compact, &Mempool::new()).unwrap();
+        assert_eq!(partial.missing(), vec![1, 2, 3]);
+        let mut txs = block.Content.content[1..].to_vec();
+        txs[0] = generate_random_signed_transaction_();
+        partial.fill(txs).unwrap();
+        assert!(partial.block().is_none());
+
+        // prefilled transactions must be in range
+        let mut malformed = compact.clone();
+        malformed.prefilled[0].0 = 4;
+        assert!(PartialBlock::new(&malformed, &mempool).is_err());
+    }
+}
@@ -0,0 +1,128 @@
+use super::addrman::AddrManager;
+use super::banman::BanManager;
+use super::message::{Message, Version};
+use super::peer::Direction;
+use super::server::Handle as ServerHandle;
+use crate::blockchain::Blockchain;
+use crate::timedata::{self, TimeData};
+use log::{info, warn};
+
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::{thread, time};
+
+// how often the outgoing connections are checked
+const CHECK_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+/// Keeps `target` outgoing connections open, to the peers given on the command line first and then to addresses
+/// learned from the network, and reconnects when peers drop. Clock samples of peers that dropped are forgotten.
+pub struct Context {
+    server: ServerHandle,
+    addrman: Arc<Mutex<AddrManager>>,
+    banman: Arc<Mutex<BanManager>>,
+    blockchain: Arc<Mutex<Blockchain>>,
+    timedata: Arc<Mutex<TimeData>>,
+    fixed: Vec<SocketAddr>,
+    target: usize,
+    listen_port: u16,
+    ifArchival: bool,
+}
+
+pub fn new(
+    server: &ServerHandle,
+    addrman: &Arc<Mutex<AddrManager>>,
+    banman: &Arc<Mutex<BanManager>>,
+    blockchain: &Arc<Mutex<Blockchain>>,
+    timedata: &Arc<Mutex<TimeData>>,
+    fixed: Vec<SocketAddr>,
+    target: usize,
+    listen_port: u16,
+    ifArchival: bool,
+) -> Context {
+    Context {
+        server: server.clone(),
+        addrman: Arc::clone(addrman),
+        banman: Arc::clone(banman),
+        blockchain: Arc::clone(blockchain),
+        timedata: Arc::clone(timedata),
+        fixed,
+        target,
+        listen_port,
+        ifArchival,
+    }
+}
+
+impl Context {
+    pub fn start(self) {
+        thread::spawn(move || {
+            loop {
+                self.maintain();
+                if let Err(e) = self.addrman.lock().unwrap().save() {
+                    warn!("Error saving the peers file: {}", e);
+                }
+                thread::sleep(CHECK_INTERVAL);
+            }
+        });
+    }
+
+    /// Open connections until the command line peers are connected and there are `target` outgoing connections,
+    /// or no address is left to try
+    fn maintain(&self) {
+        let mut connected = HashSet::<SocketAddr>::new();
+        let mut outgoing = 0;
+        for (addr, direction) in self.server.peers() {
+            connected.insert(addr);
+            if let Direction::Outgoing = direction {
+                outgoing += 1;
+            }
+        }
+        let hosts: HashSet<IpAddr> = connected.iter().map(|addr| addr.ip()).collect();
+        self.timedata.lock().unwrap().retain_sources(&hosts);
+        // banned peers are not tried again before their ban ends
+        let now = timedata::local_time();
+        let banman = self.banman.lock().unwrap();
+        let fixed: Vec<SocketAddr> = self.fixed.iter().filter(|addr| !banman.is_banned(addr, now)).cloned().collect();
+        std::mem::drop(banman);
+        // command line peers are retried every time, whatever the target
+        for addr in fixed.iter() {
+            if !connected.contains(addr) && self.connect(*addr) {
+                connected.insert(*addr);
+                outgoing += 1;
+            }
+        }
+        while outgoing < self.target {
+            let now = timedata::local_time();
+            let addr = match self.addrman.lock().unwrap().select(&connected, now) {
+                Some(addr) => addr,
+                None => break,
+            };
+            self.addrman.lock().unwrap().attempt(&addr, now);
+            // tried at most once per round, even if it fails
+            connected.insert(addr);
+            if self.banman.lock().unwrap().is_banned(&addr, now) {
+                continue;
+            }
+            if self.connect(addr) {
+                outgoing += 1;
+            } else {
+                self.addrman.lock().unwrap().failed(&addr);
+            }
+        }
+    }
+
+    fn connect(&self, addr: SocketAddr) -> bool {
+        match self.server.connect(addr) {
+            Ok(peer) => {
+                info!("Connected to outgoing peer {}", &addr);
+                let version = Version::new(&self.blockchain.lock().unwrap(), self.ifArchival, self.listen_port);
+                peer.write(Message::Version(version));
+                true
+            }
+            Err(e) => {
+                warn!("Error connecting to peer {}: {}", addr, e);
+                false
+            }
+        }
+    }
+}
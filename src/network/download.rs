@@ -0,0 +1,162 @@
+use super::peer;
+use crate::crypto::hash::H256;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Blocks requested from a single peer at the same time
+pub const MAX_IN_FLIGHT_PER_PEER: usize = 16;
+/// How long a peer gets to answer a block request before it is considered stalled
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Spreads block requests over the peers we sync from, and keeps track of which requests are still unanswered.
+/// A block is only ever requested from one peer at a time.
+pub struct DownloadManager {
+    in_flight: HashMap<H256, (SocketAddr, Instant)>, // Block Hash <-> (Peer asked, Time asked)
+    load: HashMap<SocketAddr, usize>, // Peer <-> number of its requests in flight
+    handles: HashMap<SocketAddr, peer::Handle>,
+    max_per_peer: usize,
+    timeout: Duration,
+}
+
+impl DownloadManager {
+    pub fn new(max_per_peer: usize, timeout: Duration) -> Self {
+        DownloadManager {
+            in_flight: HashMap::new(),
+            load: HashMap::new(),
+            handles: HashMap::new(),
+            max_per_peer,
+            timeout,
+        }
+    }
+
+    /// Download from `peer` from now on
+    pub fn add_peer(&mut self, peer: &peer::Handle) {
+        self.handles.insert(peer.addr(), peer.clone());
+        self.add_source(peer.addr());
+    }
+
+    fn add_source(&mut self, addr: SocketAddr) {
+        self.load.entry(addr).or_insert(0);
+    }
+
+    /// Stop downloading from `addr`. Its requests in flight become available to other peers
+    pub fn remove_peer(&mut self, addr: &SocketAddr) {
+        self.handles.remove(addr);
+        self.load.remove(addr);
+        self.in_flight.retain(|_, (peer, _)| peer != addr);
+    }
+
+    pub fn handle(&self, addr: &SocketAddr) -> Option<&peer::Handle> {
+        self.handles.get(addr)
+    }
+
+    pub fn is_in_flight(&self, hash: &H256) -> bool {
+        self.in_flight.contains_key(hash)
+    }
+
+    /// A block arrived, from whichever peer
+    pub fn received(&mut self, hash: &H256) {
+        if let Some((addr, _)) = self.in_flight.remove(hash) {
+            if let Some(load) = self.load.get_mut(&addr) {
+                *load -= 1;
+            }
+        }
+    }
+
+    /// Request `hash` from `addr` outside of `assign`, as when a compact block announced by that peer could not
+    /// be rebuilt. Returns false if the block is already in flight
+    pub fn request_from(&mut self, addr: SocketAddr, hash: H256, now: Instant) -> bool {
+        if self.in_flight.contains_key(&hash) {
+            return false;
+        }
+        if let Some(load) = self.load.get_mut(&addr) {
+            *load += 1;
+        }
+        self.in_flight.insert(hash, (addr, now));
+        true
+    }
+
+    /// Drop the peers with a request older than the timeout and return them. Their requests are re-issued by
+    /// the next `assign`
+    pub fn expire(&mut self, now: Instant) -> Vec<SocketAddr> {
+        let mut stalled = Vec::<SocketAddr>::new();
+        for (addr, asked) in self.in_flight.values() {
+            if now.duration_since(*asked) > self.timeout && !stalled.contains(addr) {
+                stalled.push(*addr);
+            }
+        }
+        for addr in stalled.iter() {
+            self.remove_peer(addr);
+        }
+        stalled
+    }
+
+    /// Assign the blocks of `wanted` that are not in flight to the least loaded peers with free slots,
+    /// in order. Returns the hashes to request from each peer
+    pub fn assign(&mut self, wanted: &[H256], now: Instant) -> Vec<(SocketAddr, Vec<H256>)> {
+        let mut requests = HashMap::<SocketAddr, Vec<H256>>::new();
+        for hash in wanted.iter() {
+            if self.in_flight.contains_key(hash) {
+                continue;
+            }
+            let max_per_peer = self.max_per_peer;
+            let peer = self
+                .load
+                .iter_mut()
+                .filter(|(_, load)| **load < max_per_peer)
+                .min_by_key(|(addr, load)| (**load, **addr));
+            let (addr, load) = match peer {
+                Some(peer) => peer,
+                None => break,
+            };
+            *load += 1;
+            self.in_flight.insert(*hash, (*addr, now));
+            requests.entry(*addr).or_insert_with(Vec::new).push(*hash);
+        }
+        requests.into_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::hash::tests::generate_random_hash;
+
+    #[test]
+    fn spread_dedupe_and_timeout() {
+        let mut download = DownloadManager::new(2, Duration::from_secs(10));
+        let a: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let b: SocketAddr = "127.0.0.1:6002".parse().unwrap();
+        download.add_source(a);
+        download.add_source(b);
+        let wanted: Vec<H256> = (0..5).map(|_| generate_random_hash()).collect();
+        let start = Instant::now();
+
+        // two slots per peer, filled alternately: the fifth block waits
+        let mut requests = download.assign(&wanted, start);
+        requests.sort();
+        assert_eq!(requests, vec![(a, vec![wanted[0], wanted[2]]), (b, vec![wanted[1], wanted[3]])]);
+        assert!(!download.is_in_flight(&wanted[4]));
+        // nothing is requested twice
+        assert!(download.assign(&wanted, start).is_empty());
+
+        // an answer frees a slot for the fifth block
+        download.received(&wanted[0]);
+        assert_eq!(download.assign(&wanted[1..], start), vec![(a, vec![wanted[4]])]);
+
+        // a answers everything, b stalls: after the timeout b is dropped and its blocks go to a
+        download.received(&wanted[2]);
+        download.received(&wanted[4]);
+        let late = start + Duration::from_secs(11);
+        assert_eq!(download.expire(late), vec![b]);
+        assert_eq!(download.assign(&[wanted[1], wanted[3]], late), vec![(a, vec![wanted[1], wanted[3]])]);
+
+        // a block asked for directly is not assigned again
+        let extra = generate_random_hash();
+        assert!(download.request_from(b, extra, late));
+        assert!(!download.request_from(a, extra, late));
+        assert!(download.assign(&[extra], late).is_empty());
+    }
+}
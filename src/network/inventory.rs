@@ -0,0 +1,68 @@
+use crate::crypto::hash::H256;
+
+use std::collections::{HashSet, VecDeque};
+
+/// Items a peer is known to have, remembered per peer
+pub const MAX_KNOWN_PER_PEER: usize = 50000;
+/// State witnesses already processed, so that the same witness is relayed only once
+pub const MAX_SEEN_WITNESSES: usize = 1000;
+
+/// A set of item hashes that forgets the oldest once it holds `capacity` of them
+#[derive(Debug, Clone)]
+pub struct InventorySet {
+    hashes: HashSet<H256>,
+    order: VecDeque<H256>,
+    capacity: usize,
+}
+
+impl InventorySet {
+    pub fn new(capacity: usize) -> Self {
+        InventorySet {
+            hashes: HashSet::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    /// Returns whether the hash is new
+    pub fn insert(&mut self, hash: H256) -> bool {
+        if !self.hashes.insert(hash) {
+            return false;
+        }
+        self.order.push_back(hash);
+        if self.order.len() > self.capacity {
+            let oldest = self.order.pop_front().unwrap();
+            self.hashes.remove(&oldest);
+        }
+        true
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.hashes.contains(hash)
+    }
+}
+
+impl Default for InventorySet {
+    fn default() -> Self {
+        InventorySet::new(MAX_KNOWN_PER_PEER)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::hash::tests::generate_random_hash;
+
+    #[test]
+    fn bounded_set() {
+        let mut set = InventorySet::new(2);
+        let hashes: Vec<H256> = (0..3).map(|_| generate_random_hash()).collect();
+        assert!(set.insert(hashes[0]));
+        assert!(!set.insert(hashes[0]));
+        assert!(set.insert(hashes[1]));
+        // the oldest is forgotten first
+        assert!(set.insert(hashes[2]));
+        assert!(!set.contains(&hashes[0]));
+        assert!(set.contains(&hashes[1]) && set.contains(&hashes[2]));
+    }
+}
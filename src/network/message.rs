@@ -1,20 +1,190 @@
 use serde::{Serialize, Deserialize};
-use crate::block::Block;
+use std::net::SocketAddr;
+use crate::block::{Block, Header};
+use crate::blockchain::Blockchain;
+use crate::script::Script;
+use crate::timedata;
+use super::addrman::MAX_ADDR_PER_MESSAGE;
+use super::compact::CompactBlock;
 use crate::crypto::hash::{H256, Hashable, H160};
 use crate::transaction::{Transaction, SignedTransaction,StateWitness};
 
+/// Version of the protocol spoken by this node
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest protocol version this node still talks to
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// Most headers sent in one message
+pub const MAX_HEADERS: usize = 2000;
+/// Most hashes in one announcement or request
+pub const MAX_INVENTORY: usize = 50000;
+/// Most hashes in a block locator, which grows with the logarithm of the height
+const MAX_LOCATOR: usize = 101;
+// room for the message tag and a length prefix, and bounds on the encoded size of the items of a list
+const ENVELOPE: usize = 16;
+const HASH_SIZE: usize = 32;
+const HEADER_SIZE: usize = 128;
+const ADDR_SIZE: usize = 32;
+const INDEX_SIZE: usize = 4;
+
+/// First message on every connection. The connecting side sends it, the accepting side answers with its own,
+/// and each side acknowledges the other's with a `Verack`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Version {
+    pub protocol_version: u32,
+    pub network_id: u32,
+    pub genesis_hash: H256,
+    pub best_height: u32,
+    pub archival: bool,
+    pub time: u128, // sender's clock, in milliseconds since the unix epoch
+    pub listen_port: u16, // where the sender accepts connections, on the address it connects from
+}
+
+impl Version {
+    pub fn new(blockchain: &Blockchain, archival: bool, listen_port: u16) -> Self {
+        Version {
+            protocol_version: PROTOCOL_VERSION,
+            network_id: blockchain.params.network_id,
+            genesis_hash: blockchain.genesis_hash,
+            best_height: blockchain.tip.1,
+            archival,
+            time: timedata::local_time(),
+            listen_port,
+        }
+    }
+
+    /// Why a peer announcing this version cannot talk to a node announcing `local`, if it cannot
+    pub fn incompatibility(&self, local: &Version) -> Option<String> {
+        if self.protocol_version < MIN_PROTOCOL_VERSION {
+            return Some(format!("protocol version {} is older than {}", self.protocol_version, MIN_PROTOCOL_VERSION));
+        }
+        if self.network_id != local.network_id {
+            return Some(format!("network id {:#x} instead of {:#x}", self.network_id, local.network_id));
+        }
+        if self.genesis_hash != local.genesis_hash {
+            return Some(format!("genesis {:?} instead of {:?}", self.genesis_hash, local.genesis_hash));
+        }
+        None
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
     Ping(String),
     Pong(String),
-    NewBlockHashes(Vec<H256>),
+    NewBlockHashes(Vec<H256>), // announces new tips
+    GetHeaders(Vec<H256>), // block locator of the sender's best header chain
+    Headers(Vec<Header>),
     GetBlocks(Vec<H256>),
     Blocks(Vec<Block>),
     NewTransactionHashes(Vec<H256>),
     GetTransactions(Vec<H256>),
     Transactions(Vec<SignedTransaction>),
     //TODO:Update State Witness, Accumulator Proof
-    NewStateWitness(Vec<(H256, u32, f32, H160, u32, u128)>,Vec<(H256,u128)>),
+    NewStateWitness(Vec<(H256, u32, u64, Script, u32, u128)>,Vec<(H256,u128)>),
+    Version(Version),
+    Verack,
+    GetAddr,
+    Addr(Vec<SocketAddr>), // listening addresses of peers the sender knows of
+    CompactBlock(CompactBlock), // announces a new tip along with its transactions in short
+    GetBlockTxn(H256, Vec<u32>), // transactions of a compact block, by index
+    BlockTxn(H256, Vec<SignedTransaction>),
 }
 
+
+/// Identifies a state witness announcement, so that it is processed and relayed once
+pub fn state_witness_id(states: &[(H256, u32, u64, Script, u32, u128)], proof: &[(H256, u128)]) -> H256 {
+    ring::digest::digest(&ring::digest::SHA256, &bincode::serialize(&(states, proof)).unwrap()).into()
+}
+
+impl Message {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Message::Ping(_) => "ping",
+            Message::Pong(_) => "pong",
+            Message::NewBlockHashes(_) => "new_block_hashes",
+            Message::GetHeaders(_) => "get_headers",
+            Message::Headers(_) => "headers",
+            Message::GetBlocks(_) => "get_blocks",
+            Message::Blocks(_) => "blocks",
+            Message::NewTransactionHashes(_) => "new_transaction_hashes",
+            Message::GetTransactions(_) => "get_transactions",
+            Message::Transactions(_) => "transactions",
+            Message::NewStateWitness(_, _) => "new_state_witness",
+            Message::Version(_) => "version",
+            Message::Verack => "verack",
+            Message::GetAddr => "get_addr",
+            Message::Addr(_) => "addr",
+            Message::CompactBlock(_) => "compact_block",
+            Message::GetBlockTxn(_, _) => "get_block_txn",
+            Message::BlockTxn(_, _) => "block_txn",
+        }
+    }
+
+    /// Largest encoding accepted for a message of this type, in bytes. Messages carrying blocks, transactions
+    /// or states are only bounded by the frame size
+    pub fn size_limit(&self) -> Option<usize> {
+        match self {
+            Message::Ping(_) | Message::Pong(_) | Message::Version(_) => Some(1024),
+            Message::Verack | Message::GetAddr => Some(ENVELOPE),
+            Message::NewBlockHashes(_)
+            | Message::GetBlocks(_)
+            | Message::NewTransactionHashes(_)
+            | Message::GetTransactions(_) => Some(ENVELOPE + MAX_INVENTORY * HASH_SIZE),
+            Message::GetHeaders(_) => Some(ENVELOPE + MAX_LOCATOR * HASH_SIZE),
+            Message::Headers(_) => Some(ENVELOPE + MAX_HEADERS * HEADER_SIZE),
+            Message::Addr(_) => Some(ENVELOPE + MAX_ADDR_PER_MESSAGE * ADDR_SIZE),
+            Message::GetBlockTxn(_, _) => Some(ENVELOPE + HASH_SIZE + MAX_INVENTORY * INDEX_SIZE),
+            Message::Blocks(_)
+            | Message::Transactions(_)
+            | Message::NewStateWitness(_, _)
+            | Message::CompactBlock(_)
+            | Message::BlockTxn(_, _) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::tests::temp_data_dir;
+    use crate::params::ChainParams;
+
+    #[test]
+    fn version_compatibility() {
+        let blockchain = Blockchain::new(&temp_data_dir("version"), ChainParams::default()).unwrap();
+        let local = Version::new(&blockchain, true, 6000);
+        let mut remote = Version::new(&blockchain, false, 6001);
+        assert_eq!(remote.incompatibility(&local), None);
+        remote.protocol_version = MIN_PROTOCOL_VERSION - 1;
+        assert!(remote.incompatibility(&local).is_some());
+        let mut remote = Version::new(&blockchain, false, 6001);
+        remote.network_id += 1;
+        assert!(remote.incompatibility(&local).is_some());
+        let mut remote = Version::new(&blockchain, false, 6001);
+        remote.genesis_hash = H256::default();
+        assert!(remote.incompatibility(&local).is_some());
+    }
+
+    #[test]
+    fn size_limits() {
+        // full messages of each bounded type fit their limit
+        let blockchain = Blockchain::new(&temp_data_dir("size_limits"), ChainParams::default()).unwrap();
+        let header = blockchain.Headers.get(&blockchain.genesis_hash).unwrap().0.clone();
+        let addr: SocketAddr = "[::1]:6000".parse().unwrap();
+        let full = vec![
+            Message::Version(Version::new(&blockchain, true, 6000)),
+            Message::Verack,
+            Message::GetBlocks(vec![H256::default(); MAX_INVENTORY]),
+            Message::GetHeaders(vec![H256::default(); MAX_LOCATOR]),
+            Message::Headers(vec![header; MAX_HEADERS]),
+            Message::Addr(vec![addr; MAX_ADDR_PER_MESSAGE]),
+            Message::GetBlockTxn(H256::default(), vec![u32::max_value(); MAX_INVENTORY]),
+        ];
+        for msg in full.iter() {
+            let size = bincode::serialize(msg).unwrap().len();
+            assert!(size <= msg.size_limit().unwrap(), "{} of {} bytes", msg.name(), size);
+        }
+        let too_many = Message::GetBlocks(vec![H256::default(); MAX_INVENTORY + 1]);
+        assert!(bincode::serialize(&too_many).unwrap().len() > too_many.size_limit().unwrap());
+    }
+}
@@ -1,4 +1,12 @@
+pub mod addrman;
+pub mod banman;
+pub mod compact;
+pub mod connector;
+pub mod download;
+pub mod inventory;
 pub mod message;
 pub mod peer;
+pub mod ratelimit;
+pub mod secure;
 pub mod server;
 pub mod worker;
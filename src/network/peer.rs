@@ -1,10 +1,19 @@
 use super::message;
+use super::inventory::InventorySet;
+use super::ratelimit::{Limits, RateLimiter};
+use super::secure::{Cipher, Handshake, Identity};
+use crate::crypto::hash::{H160, H256};
 use log::{trace, warn};
 use mio;
 use mio_extras::channel;
 use std::convert::TryInto;
 use std::io::{Read, Write};
 use std::sync::mpsc;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Peers that have not finished the handshake this long after connecting are dropped
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(60);
 
 enum DecodeState {
     Length,
@@ -15,6 +24,10 @@ pub enum ReadResult {
     Continue,
     Message(Vec<u8>),
     EOF,
+    /// The peer announced a frame larger than the limit, of this many bytes
+    Oversized(usize),
+    /// The first frame of a secure transport, to finish the handshake with
+    Hello(Vec<u8>),
 }
 
 pub struct ReadContext {
@@ -22,7 +35,10 @@ pub struct ReadContext {
     buffer: Vec<u8>,
     msg_length: usize,
     read_length: usize,
+    max_frame_size: usize,
     state: DecodeState,
+    awaiting_hello: bool,
+    opening: Option<Cipher>,
 }
 
 impl ReadContext {
@@ -45,6 +61,10 @@ impl ReadContext {
                         DecodeState::Length => {
                             let message_length =
                                 u32::from_be_bytes(self.buffer[0..4].try_into().unwrap());
+                            // never allocate what the peer claims before checking it
+                            if message_length as usize > self.max_frame_size {
+                                return Ok(ReadResult::Oversized(message_length as usize));
+                            }
                             self.state = DecodeState::Payload;
                             self.read_length = 0;
                             self.msg_length = message_length as usize;
@@ -60,7 +80,20 @@ impl ReadContext {
                             self.read_length = 0;
                             self.msg_length = std::mem::size_of::<u32>();
                             trace!("Received full message");
-                            Ok(ReadResult::Message(new_payload))
+                            if self.awaiting_hello {
+                                self.awaiting_hello = false;
+                                return Ok(ReadResult::Hello(new_payload));
+                            }
+                            match self.opening.as_mut() {
+                                Some(cipher) => match cipher.open(new_payload) {
+                                    Some(payload) => Ok(ReadResult::Message(payload)),
+                                    None => Err(std::io::Error::new(
+                                        std::io::ErrorKind::InvalidData,
+                                        "frame failed authentication",
+                                    )),
+                                },
+                                None => Ok(ReadResult::Message(new_payload)),
+                            }
                         }
                     }
                 } else {
@@ -91,6 +124,9 @@ pub struct WriteContext {
     msg_length: usize,
     written_length: usize,
     state: WriteState,
+    hello: Option<Vec<u8>>,
+    secure: bool,
+    sealing: Option<Cipher>,
 }
 
 impl WriteContext {
@@ -120,13 +156,21 @@ impl WriteContext {
                         // if the previous message has been fully written, try to get the next message
                         // first flush the writer
                         self.writer.flush()?;
-                        let msg = match self.queue.try_recv() {
-                            Ok(msg) => msg,
-                            Err(e) => match e {
-                                mpsc::TryRecvError::Empty => return Ok(WriteResult::Complete),
-                                mpsc::TryRecvError::Disconnected => {
-                                    return Ok(WriteResult::ChanClosed);
-                                }
+                        // on a secure transport, our hello goes first and messages wait for the session keys
+                        let msg = match self.hello.take() {
+                            Some(hello) => hello,
+                            None if self.secure && self.sealing.is_none() => return Ok(WriteResult::Complete),
+                            None => match self.queue.try_recv() {
+                                Ok(msg) => match self.sealing.as_mut() {
+                                    Some(cipher) => cipher.seal(msg),
+                                    None => msg,
+                                },
+                                Err(e) => match e {
+                                    mpsc::TryRecvError::Empty => return Ok(WriteResult::Complete),
+                                    mpsc::TryRecvError::Disconnected => {
+                                        return Ok(WriteResult::ChanClosed);
+                                    }
+                                },
                             },
                         };
 
@@ -158,7 +202,16 @@ impl WriteContext {
 pub fn new(
     stream: mio::net::TcpStream,
     direction: Direction,
+    limits: &Limits,
+    identity: Option<&Arc<Identity>>,
 ) -> std::io::Result<(Context, Handle)> {
+    let (handshake, hello) = match identity {
+        Some(identity) => {
+            let (handshake, hello) = Handshake::new(identity, direction);
+            (Some(handshake), Some(hello))
+        }
+        None => (None, None),
+    };
     let reader_stream = stream.try_clone()?;
     let writer_stream = stream.try_clone()?;
     let addr = stream.peer_addr()?;
@@ -168,7 +221,10 @@ pub fn new(
         buffer: vec![0; std::mem::size_of::<u32>()],
         msg_length: std::mem::size_of::<u32>(),
         read_length: 0,
+        max_frame_size: limits.max_frame_size,
         state: DecodeState::Length,
+        awaiting_hello: identity.is_some(),
+        opening: None,
     };
     let bufwriter = std::io::BufWriter::new(writer_stream);
     let (write_sender, write_receiver) = channel::channel();
@@ -180,10 +236,16 @@ pub fn new(
         msg_length: 0,
         written_length: 0,
         state: WriteState::Payload,
+        hello,
+        secure: identity.is_some(),
+        sealing: None,
     };
     let handle = Handle {
         write_queue: write_sender,
         addr,
+        direction,
+        status: Arc::new(Mutex::new(Status::default())),
+        connected_at: Instant::now(),
     };
     let ctx = Context {
         addr,
@@ -192,6 +254,8 @@ pub fn new(
         writer: write_ctx,
         handle: handle.clone(),
         direction,
+        limiter: RateLimiter::new(limits, std::time::Instant::now()),
+        handshake,
     };
     Ok((ctx, handle))
 }
@@ -209,15 +273,128 @@ pub struct Context {
     pub writer: WriteContext,
     pub handle: Handle,
     pub direction: Direction,
+    pub limiter: RateLimiter,
+    pub handshake: Option<Handshake>,
+}
+
+impl Context {
+    /// Check the peer's hello and switch both directions to the session keys. Returns the peer's node address
+    pub fn finish_handshake(&mut self, hello: &[u8]) -> Result<H160, String> {
+        let handshake = match self.handshake.take() {
+            Some(handshake) => handshake,
+            None => return Err("no handshake in progress".to_string()),
+        };
+        let session = handshake.finish(hello)?;
+        self.reader.opening = Some(session.opening);
+        self.writer.sealing = Some(session.sealing);
+        self.handle.status().node_address = Some(session.peer_address);
+        Ok(session.peer_address)
+    }
+}
+
+/// What we know about the peer, shared by every handle of the connection
+#[derive(Debug, Default)]
+pub struct Status {
+    /// The peer's version, once we accepted it
+    pub version: Option<message::Version>,
+    /// Whether the peer acknowledged our version
+    pub verack_received: bool,
+    /// We asked the peer for addresses and it has not answered yet
+    pub addr_requested: bool,
+    /// Sum of the scores of the peer's misbehavior, it is banned once this reaches `banman::BAN_THRESHOLD`
+    pub misbehavior: u32,
+    /// Node address of the peer's key, known only over a secure transport
+    pub node_address: Option<H160>,
+    /// Blocks, transactions and state witnesses the peer announced, sent or was sent
+    pub known: InventorySet,
 }
 
 #[derive(Clone)]
 pub struct Handle {
     addr: std::net::SocketAddr,
+    direction: Direction,
     write_queue: channel::Sender<Vec<u8>>,
+    status: Arc<Mutex<Status>>,
+    connected_at: Instant,
 }
 
 impl Handle {
+    pub fn status(&self) -> MutexGuard<Status> {
+        self.status.lock().unwrap()
+    }
+
+    /// Both sides accepted each other's version
+    pub fn handshake_done(&self) -> bool {
+        let status = self.status();
+        status.version.is_some() && status.verack_received
+    }
+
+    /// Whether `msg` may be processed now: until the handshake is done, nothing but the handshake itself
+    pub fn accepts(&self, msg: &message::Message) -> bool {
+        match msg {
+            message::Message::Version(_) | message::Message::Verack => true,
+            _ => self.handshake_done(),
+        }
+    }
+
+    /// Whether an `Addr` from the peer answers a `GetAddr` of ours. Only one answer is taken per request
+    pub fn answers_addr_request(&self) -> bool {
+        std::mem::replace(&mut self.status().addr_requested, false)
+    }
+
+    /// The peer connected more than `HANDSHAKE_TIMEOUT` before `now` and still has not finished the handshake
+    pub fn handshake_expired(&self, now: Instant) -> bool {
+        !self.handshake_done() && now.saturating_duration_since(self.connected_at) >= HANDSHAKE_TIMEOUT
+    }
+
+    pub fn addr(&self) -> std::net::SocketAddr {
+        self.addr
+    }
+
+    /// The peer has these items, they are not announced to it
+    pub fn mark_known(&self, hashes: &[H256]) {
+        let mut status = self.status();
+        for hash in hashes.iter() {
+            status.known.insert(*hash);
+        }
+    }
+
+    /// The part of an announcement the peer has not seen yet, or `None` if it has seen all of it. The items
+    /// are remembered as known. Other messages are returned as they are
+    pub fn unannounced(&self, msg: &message::Message) -> Option<message::Message> {
+        let mut status = self.status();
+        match msg {
+            message::Message::NewBlockHashes(hashes) => {
+                let hashes: Vec<H256> = hashes.iter().cloned().filter(|hash| status.known.insert(*hash)).collect();
+                if hashes.is_empty() { None } else { Some(message::Message::NewBlockHashes(hashes)) }
+            }
+            message::Message::NewTransactionHashes(hashes) => {
+                let hashes: Vec<H256> = hashes.iter().cloned().filter(|hash| status.known.insert(*hash)).collect();
+                if hashes.is_empty() { None } else { Some(message::Message::NewTransactionHashes(hashes)) }
+            }
+            message::Message::NewStateWitness(states, proof) => {
+                if status.known.insert(message::state_witness_id(states, proof)) { Some(msg.clone()) } else { None }
+            }
+            message::Message::CompactBlock(compact) => {
+                if status.known.insert(compact.hash()) { Some(msg.clone()) } else { None }
+            }
+            _ => Some(msg.clone()),
+        }
+    }
+
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    /// Where the peer accepts connections: the address we connected to, or, for a peer that connected to us, the
+    /// port its version names on the address it connects from
+    pub fn listen_addr(&self) -> std::net::SocketAddr {
+        match (self.direction, self.status().version.as_ref()) {
+            (Direction::Incoming, Some(version)) => std::net::SocketAddr::new(self.addr.ip(), version.listen_port),
+            _ => self.addr,
+        }
+    }
+
     pub fn write(&self, msg: message::Message) {
         // TODO: return result
         let buffer = bincode::serialize(&msg).unwrap();
@@ -226,3 +403,71 @@ impl Handle {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::hash::tests::generate_random_hash;
+    use message::Message;
+
+    #[test]
+    fn announce_only_unknown_items() {
+        let (write_queue, _) = channel::channel();
+        let handle = Handle {
+            addr: "127.0.0.1:6001".parse().unwrap(),
+            direction: Direction::Outgoing,
+            write_queue,
+            status: Arc::new(Mutex::new(Status::default())),
+            connected_at: Instant::now(),
+        };
+        let hashes: Vec<H256> = (0..3).map(|_| generate_random_hash()).collect();
+        // the peer announced the first item itself
+        handle.mark_known(&hashes[..1]);
+        match handle.unannounced(&Message::NewBlockHashes(hashes.clone())) {
+            Some(Message::NewBlockHashes(announced)) => assert_eq!(announced, hashes[1..].to_vec()),
+            _ => panic!("expected the unknown hashes"),
+        }
+        // nothing is announced twice
+        assert!(handle.unannounced(&Message::NewBlockHashes(hashes.clone())).is_none());
+        let witness = Message::NewStateWitness(vec![], vec![(hashes[0], 7)]);
+        assert!(handle.unannounced(&witness).is_some());
+        assert!(handle.unannounced(&witness).is_none());
+        assert!(handle.unannounced(&Message::Ping(String::new())).is_some());
+    }
+
+    #[test]
+    fn handshake_gates_messages() {
+        let (write_queue, _) = channel::channel();
+        let connected_at = Instant::now();
+        let handle = Handle {
+            addr: "127.0.0.1:6001".parse().unwrap(),
+            direction: Direction::Incoming,
+            write_queue,
+            status: Arc::new(Mutex::new(Status::default())),
+            connected_at,
+        };
+        let ping = Message::Ping(String::new());
+        assert!(!handle.accepts(&ping));
+        assert!(handle.accepts(&Message::Verack));
+        // the peer sent its version but has not acknowledged ours yet
+        handle.status().version = Some(message::Version {
+            protocol_version: message::PROTOCOL_VERSION,
+            network_id: 0,
+            genesis_hash: H256::default(),
+            best_height: 0,
+            archival: false,
+            time: 0,
+            listen_port: 6000,
+        });
+        assert!(!handle.accepts(&ping));
+        assert!(handle.handshake_expired(connected_at + HANDSHAKE_TIMEOUT));
+        handle.status().verack_received = true;
+        assert!(handle.accepts(&ping));
+        assert!(!handle.handshake_expired(connected_at + HANDSHAKE_TIMEOUT));
+        // addresses are only taken once, in answer to our request
+        assert!(!handle.answers_addr_request());
+        handle.status().addr_requested = true;
+        assert!(handle.answers_addr_request());
+        assert!(!handle.answers_addr_request());
+    }
+}
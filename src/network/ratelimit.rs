@@ -0,0 +1,113 @@
+use std::time::{Duration, Instant};
+
+/// How many seconds worth of traffic a peer may send in one burst
+const BURST_SECONDS: u64 = 4;
+
+/// Size and rate limits applied to every peer
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Largest frame read from a peer, in bytes
+    pub max_frame_size: usize,
+    pub bytes_per_sec: u64,
+    pub messages_per_sec: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_frame_size: 32 * 1024 * 1024,
+            bytes_per_sec: 16 * 1024 * 1024,
+            messages_per_sec: 1000,
+        }
+    }
+}
+
+/// Holds up to `capacity` tokens and refills at `rate` tokens per second
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: u64,
+    tokens: u64,
+    rate: u64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket
+    pub fn new(capacity: u64, rate: u64, now: Instant) -> Self {
+        TokenBucket {
+            capacity,
+            tokens: capacity,
+            rate,
+            last_refill: now,
+        }
+    }
+
+    /// Take `amount` tokens if the bucket holds that many. Returns whether it did
+    pub fn take(&mut self, amount: u64, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        let refill = (elapsed.as_millis() as u64).saturating_mul(self.rate) / 1000;
+        if refill > 0 {
+            self.tokens = std::cmp::min(self.capacity, self.tokens.saturating_add(refill));
+            // only move forward by the time the refill accounts for, so that slow trickles are not lost
+            self.last_refill += Duration::from_millis(refill * 1000 / self.rate.max(1));
+        }
+        if self.tokens < amount {
+            return false;
+        }
+        self.tokens -= amount;
+        true
+    }
+}
+
+/// The byte and message buckets of one peer
+pub struct RateLimiter {
+    bytes: TokenBucket,
+    messages: TokenBucket,
+}
+
+impl RateLimiter {
+    pub fn new(limits: &Limits, now: Instant) -> Self {
+        // a single frame of the largest size must always fit in a full bucket
+        let byte_capacity = std::cmp::max(limits.bytes_per_sec * BURST_SECONDS, limits.max_frame_size as u64);
+        RateLimiter {
+            bytes: TokenBucket::new(byte_capacity, limits.bytes_per_sec, now),
+            messages: TokenBucket::new(limits.messages_per_sec * BURST_SECONDS, limits.messages_per_sec, now),
+        }
+    }
+
+    /// Account for a message of `size` bytes. Returns false if the peer exceeded either rate
+    pub fn allow(&mut self, size: usize, now: Instant) -> bool {
+        self.messages.take(1, now) && self.bytes.take(size as u64, now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10, 5, start);
+        // a full burst, then nothing until the bucket refills
+        assert!(bucket.take(10, start));
+        assert!(!bucket.take(1, start));
+        assert!(!bucket.take(1, start + Duration::from_millis(100)));
+        assert!(bucket.take(1, start + Duration::from_millis(200)));
+        // refills never exceed the capacity
+        let later = start + Duration::from_secs(60);
+        assert!(bucket.take(10, later));
+        assert!(!bucket.take(1, later));
+
+        let limits = Limits { max_frame_size: 100, bytes_per_sec: 10, messages_per_sec: 2 };
+        let mut limiter = RateLimiter::new(&limits, start);
+        // the largest frame fits although it is more than a burst of bytes
+        assert!(limiter.allow(100, start));
+        assert!(!limiter.allow(1, start));
+        let mut limiter = RateLimiter::new(&limits, start);
+        for _ in 0..8 {
+            assert!(limiter.allow(1, start));
+        }
+        assert!(!limiter.allow(1, start));
+    }
+}
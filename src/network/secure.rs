@@ -0,0 +1,196 @@
+use super::peer::Direction;
+use crate::crypto::hash::H160;
+use crate::crypto::key_pair;
+use ring::signature::{self, Ed25519KeyPair, KeyPair};
+use ring::{aead, agreement, hkdf, rand};
+use serde::{Deserialize, Serialize};
+
+use std::sync::Arc;
+
+/// Signed along with the ephemeral key and mixed into the session keys, so that neither can be reused elsewhere
+const CONTEXT: &[u8] = b"bitcoin p2p transport v1";
+
+/// The node key pair, used to authenticate the transport handshake
+pub struct Identity {
+    key_pair: Ed25519KeyPair,
+    pub address: H160,
+}
+
+impl Identity {
+    pub fn new(key_pair: Ed25519KeyPair) -> Self {
+        let address = node_address(key_pair.public_key().as_ref());
+        Identity { key_pair, address }
+    }
+}
+
+/// The node address of a public key, the same as the address its coins are paid to
+pub fn node_address(public_key: &[u8]) -> H160 {
+    key_pair::address(public_key)
+}
+
+/// First frame each side sends, in plaintext: a fresh X25519 key signed by the node key
+#[derive(Serialize, Deserialize)]
+struct Hello {
+    ephemeral: Vec<u8>,
+    public_key: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+/// Our half of a transport handshake, waiting for the peer's `Hello`
+pub struct Handshake {
+    ephemeral: agreement::EphemeralPrivateKey,
+    ephemeral_public: Vec<u8>,
+    identity: Arc<Identity>,
+    direction: Direction,
+}
+
+/// Keys of an established session, one per direction, and the authenticated identity of the peer
+pub struct Session {
+    pub sealing: Cipher,
+    pub opening: Cipher,
+    pub peer_address: H160,
+}
+
+impl Handshake {
+    /// Start a handshake. Returns it along with the `Hello` frame to send
+    pub fn new(identity: &Arc<Identity>, direction: Direction) -> (Self, Vec<u8>) {
+        let rng = rand::SystemRandom::new();
+        let ephemeral = agreement::EphemeralPrivateKey::generate(&agreement::X25519, &rng).unwrap();
+        let ephemeral_public = ephemeral.compute_public_key().unwrap().as_ref().to_vec();
+        let hello = Hello {
+            ephemeral: ephemeral_public.clone(),
+            public_key: identity.key_pair.public_key().as_ref().to_vec(),
+            signature: identity.key_pair.sign(&signed_part(&ephemeral_public)).as_ref().to_vec(),
+        };
+        let handshake = Handshake {
+            ephemeral,
+            ephemeral_public,
+            identity: Arc::clone(identity),
+            direction,
+        };
+        (handshake, bincode::serialize(&hello).unwrap())
+    }
+
+    /// Check the peer's `Hello` frame and derive the session keys. A peer replaying someone else's `Hello`
+    /// passes this check, but cannot open or seal anything without the ephemeral private key
+    pub fn finish(self, frame: &[u8]) -> Result<Session, String> {
+        let hello: Hello = bincode::deserialize(frame).map_err(|e| format!("malformed hello: {}", e))?;
+        signature::UnparsedPublicKey::new(&signature::ED25519, &hello.public_key)
+            .verify(&signed_part(&hello.ephemeral), &hello.signature)
+            .map_err(|_| "invalid hello signature".to_string())?;
+        let local_public = self.identity.key_pair.public_key().as_ref().to_vec();
+        // the transcript, in the same order on both sides: connecting side first
+        let transcript = match self.direction {
+            Direction::Outgoing => [&self.ephemeral_public[..], &hello.ephemeral, &local_public, &hello.public_key].concat(),
+            Direction::Incoming => [&hello.ephemeral[..], &self.ephemeral_public, &hello.public_key, &local_public].concat(),
+        };
+        let peer_ephemeral = agreement::UnparsedPublicKey::new(&agreement::X25519, &hello.ephemeral);
+        let (outgoing_key, incoming_key) = agreement::agree_ephemeral(
+            self.ephemeral,
+            &peer_ephemeral,
+            "invalid ephemeral key".to_string(),
+            |shared| {
+                let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, CONTEXT).extract(shared);
+                Ok((derive_key(&prk, b"connecting side", &transcript), derive_key(&prk, b"accepting side", &transcript)))
+            },
+        )?;
+        let (sealing, opening) = match self.direction {
+            Direction::Outgoing => (outgoing_key, incoming_key),
+            Direction::Incoming => (incoming_key, outgoing_key),
+        };
+        Ok(Session {
+            sealing: Cipher::new(sealing),
+            opening: Cipher::new(opening),
+            peer_address: node_address(&hello.public_key),
+        })
+    }
+}
+
+fn signed_part(ephemeral: &[u8]) -> Vec<u8> {
+    [CONTEXT, ephemeral].concat()
+}
+
+fn derive_key(prk: &hkdf::Prk, label: &[u8], transcript: &[u8]) -> aead::LessSafeKey {
+    let info = [label, transcript];
+    let okm = prk.expand(&info, &aead::CHACHA20_POLY1305).unwrap();
+    aead::LessSafeKey::new(aead::UnboundKey::from(okm))
+}
+
+/// ChaCha20-Poly1305 for one direction of a session. Frames are numbered, so they cannot be replayed,
+/// dropped or reordered without the next one failing to open
+pub struct Cipher {
+    key: aead::LessSafeKey,
+    counter: u64,
+}
+
+impl Cipher {
+    fn new(key: aead::LessSafeKey) -> Self {
+        Cipher { key, counter: 0 }
+    }
+
+    fn next_nonce(&mut self) -> aead::Nonce {
+        let mut nonce = [0u8; aead::NONCE_LEN];
+        nonce[4..].copy_from_slice(&self.counter.to_be_bytes());
+        self.counter += 1;
+        aead::Nonce::assume_unique_for_key(nonce)
+    }
+
+    pub fn seal(&mut self, mut payload: Vec<u8>) -> Vec<u8> {
+        let nonce = self.next_nonce();
+        self.key.seal_in_place_append_tag(nonce, aead::Aad::empty(), &mut payload).unwrap();
+        payload
+    }
+
+    pub fn open(&mut self, mut frame: Vec<u8>) -> Option<Vec<u8>> {
+        let nonce = self.next_nonce();
+        let length = self.key.open_in_place(nonce, aead::Aad::empty(), &mut frame).ok()?.len();
+        frame.truncate(length);
+        Some(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::key_pair;
+
+    fn connect(a: &Arc<Identity>, b: &Arc<Identity>) -> (Session, Session) {
+        let (a_handshake, a_hello) = Handshake::new(a, Direction::Outgoing);
+        let (b_handshake, b_hello) = Handshake::new(b, Direction::Incoming);
+        (a_handshake.finish(&b_hello).unwrap(), b_handshake.finish(&a_hello).unwrap())
+    }
+
+    #[test]
+    fn handshake_and_frames() {
+        let a = Arc::new(Identity::new(key_pair::random()));
+        let b = Arc::new(Identity::new(key_pair::random()));
+
+        // a tampered hello is rejected
+        let (_, a_hello) = Handshake::new(&a, Direction::Outgoing);
+        let (b_handshake, _) = Handshake::new(&b, Direction::Incoming);
+        let mut forged: Hello = bincode::deserialize(&a_hello).unwrap();
+        forged.ephemeral[0] ^= 1;
+        assert!(b_handshake.finish(&bincode::serialize(&forged).unwrap()).is_err());
+
+        let (mut a_session, mut b_session) = connect(&a, &b);
+        assert_eq!(a_session.peer_address, b.address);
+        assert_eq!(b_session.peer_address, a.address);
+        let first = a_session.sealing.seal(b"first".to_vec());
+        let second = a_session.sealing.seal(b"second".to_vec());
+        assert_ne!(first[..5], b"first"[..]);
+        assert_eq!(b_session.opening.open(first.clone()).unwrap(), b"first".to_vec());
+        assert_eq!(b_session.opening.open(second).unwrap(), b"second".to_vec());
+        // a replayed or tampered frame does not open
+        assert!(b_session.opening.open(first).is_none());
+        let mut reply = b_session.sealing.seal(b"reply".to_vec());
+        reply[0] ^= 1;
+        assert!(a_session.opening.open(reply).is_none());
+
+        // frames of one session do not open in another
+        let (mut c_session, mut d_session) = connect(&a, &b);
+        let frame = c_session.sealing.seal(b"first".to_vec());
+        let (_, mut other) = connect(&a, &b);
+        assert!(other.opening.open(frame.clone()).is_none());
+        assert!(d_session.opening.open(frame).is_some());
+    }
+}
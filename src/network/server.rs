@@ -1,22 +1,47 @@
 use super::message;
 use super::peer::{self, ReadResult, WriteResult};
+use super::ratelimit::Limits;
+use super::secure::Identity;
 use crossbeam::channel as cbchannel;
 use log::{debug, error, info, trace, warn};
 use mio::{self, net};
 use mio_extras::channel;
-use std::sync::mpsc;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{mpsc, Arc, Mutex, MutexGuard};
 use std::{thread, fs};
 
 const MAX_INCOMING_CLIENT: usize = 256;
 const MAX_EVENT: usize = 1024;
+// connecting blocks the event loop, so give up on unresponsive addresses quickly
+const CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+// how often peers are checked for an unfinished handshake, when no event wakes the event loop earlier
+const HANDSHAKE_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Traffic counters of the P2P layer since start
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct Metrics {
+    pub bytes_received: u64,
+    pub messages_received: u64,
+    pub received_by_type: BTreeMap<String, u64>,
+    /// Frames larger than the maximum frame size
+    pub oversized_frames: u64,
+    /// Messages larger than the limit of their type
+    pub oversized_messages: u64,
+    /// Peers disconnected for sending too many bytes or messages
+    pub rate_limited_peers: u64,
+}
 
 pub fn new(
     addr: std::net::SocketAddr,
     msg_sink: cbchannel::Sender<(Vec<u8>, peer::Handle)>,
+    limits: Limits,
+    identity: Option<Arc<Identity>>,
 ) -> std::io::Result<(Context, Handle)> {
     let (control_signal_sender, control_signal_receiver) = channel::channel();
     let handle = Handle {
         control_chan: control_signal_sender,
+        metrics: Arc::new(Mutex::new(Metrics::default())),
     };
     let ctx = Context {
         peers: slab::Slab::new(),
@@ -25,6 +50,8 @@ pub fn new(
         poll: mio::Poll::new()?,
         control_chan: control_signal_receiver,
         new_msg_chan: msg_sink,
+        limits,
+        identity,
         _handle: handle.clone(),
     };
     Ok((ctx, handle))
@@ -37,6 +64,8 @@ pub struct Context {
     poll: mio::Poll,
     control_chan: channel::Receiver<ControlSignal>,
     new_msg_chan: cbchannel::Sender<(Vec<u8>, peer::Handle)>,
+    limits: Limits,
+    identity: Option<Arc<Identity>>, // set when peers talk over the secure transport
     _handle: Handle,
 }
 
@@ -79,7 +108,7 @@ impl Context {
             mio::Ready::readable(),
             mio::PollOpt::edge(),
         )?;
-        let (ctx, handle) = peer::new(stream, direction)?;
+        let (ctx, handle) = peer::new(stream, direction, &self.limits, self.identity.as_ref())?;
 
         // register the writer queue
         self.poll.register(
@@ -94,6 +123,10 @@ impl Context {
         // record the key of this peer
         self.peer_list.push(key);
         trace!("Registering peer with event token={}", key);
+        // a secure transport starts with our hello, before anything is queued
+        if self.identity.is_some() {
+            self.register_write_interest(key)?;
+        }
         Ok(handle)
     }
 
@@ -101,7 +134,7 @@ impl Context {
     fn connect(&mut self, addr: &std::net::SocketAddr) -> std::io::Result<peer::Handle> {
         // we need to estabilsh a stdlib tcp stream, since we need it to block
         debug!("Establishing connection to peer {}", addr);
-        let stream = std::net::TcpStream::connect(addr)?;
+        let stream = std::net::TcpStream::connect_timeout(addr, CONNECT_TIMEOUT)?;
         let mio_stream = net::TcpStream::from_stream(stream)?;
         self.register(mio_stream, peer::Direction::Outgoing)
     }
@@ -143,14 +176,61 @@ impl Context {
             }
             ControlSignal::BroadcastMessage(msg) => {
                 trace!("Processing BroadcastMessage command");
+                // peers still in the handshake would not accept anything else, and announcements only go to
+                // peers that have not seen the items
                 for peer_id in &self.peer_list {
-                    self.peers[*peer_id].handle.write(msg.clone());
+                    let handle = &self.peers[*peer_id].handle;
+                    if !handle.handshake_done() {
+                        continue;
+                    }
+                    if let Some(msg) = handle.unannounced(&msg) {
+                        handle.write(msg);
+                    }
+                }
+            }
+            ControlSignal::ListPeers(result_chan) => {
+                trace!("Processing ListPeers command");
+                let peers = self
+                    .peer_list
+                    .iter()
+                    .map(|id| (self.peers[*id].addr, self.peers[*id].handle.direction()))
+                    .collect();
+                result_chan.send(peers).unwrap();
+            }
+            ControlSignal::DisconnectPeer(addr) => {
+                trace!("Processing DisconnectPeer command");
+                let peer_id = self.peer_list.iter().cloned().find(|id| self.peers[*id].addr == addr);
+                if let Some(peer_id) = peer_id {
+                    info!("Disconnecting peer {}", addr);
+                    self.remove_peer(peer_id);
                 }
             }
         }
         Ok(())
     }
 
+    /// Drop the peers that did not finish the handshake in time.
+    fn drop_stalled_handshakes(&mut self) {
+        let now = std::time::Instant::now();
+        let stalled: Vec<usize> = self
+            .peer_list
+            .iter()
+            .cloned()
+            .filter(|id| self.peers[*id].handle.handshake_expired(now))
+            .collect();
+        for peer_id in stalled {
+            warn!("Peer {} did not finish the handshake in time, disconnecting", self.peers[peer_id].addr);
+            self.remove_peer(peer_id);
+        }
+    }
+
+    /// Drop a peer from the connection set, which closes its socket.
+    fn remove_peer(&mut self, peer_id: usize) {
+        self.peers.remove(peer_id);
+        let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
+        self.peer_list.swap_remove(index);
+    }
+
     fn register_write_interest(&mut self, peer_id: usize) -> std::io::Result<()> {
         trace!("Registering socket write interest for peer {}", peer_id);
         let peer = &mut self.peers[peer_id];
@@ -169,6 +249,7 @@ impl Context {
     fn process_readable(&mut self, peer_id: usize) -> std::io::Result<()> {
         // we are using edge-triggered events, loop until block
         let peer = &mut self.peers[peer_id];
+        let mut keyed = false;
         loop {
             match peer.reader.read() {
                 Ok(ReadResult::EOF) => {
@@ -187,9 +268,46 @@ impl Context {
                 Ok(ReadResult::Message(m)) => {
                     trace!("Peer {} yield message", peer_id);
                     // we just received a full message
+                    let mut metrics = self._handle.metrics();
+                    metrics.bytes_received += m.len() as u64;
+                    metrics.messages_received += 1;
+                    if !peer.limiter.allow(m.len(), std::time::Instant::now()) {
+                        metrics.rate_limited_peers += 1;
+                        std::mem::drop(metrics);
+                        warn!("Peer {} exceeded its rate limit, disconnecting", peer.addr);
+                        self.peers.remove(peer_id);
+                        let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
+                        self.peer_list.swap_remove(index);
+                        break;
+                    }
+                    std::mem::drop(metrics);
                     self.new_msg_chan.send((m, peer.handle.clone())).unwrap();
                     continue;
                 }
+                Ok(ReadResult::Hello(hello)) => {
+                    match peer.finish_handshake(&hello) {
+                        Ok(node_address) => {
+                            debug!("Secure transport with peer {} established, node address {:?}", peer.addr, node_address);
+                            keyed = true;
+                            continue;
+                        }
+                        Err(e) => {
+                            warn!("Secure transport handshake with peer {} failed, disconnecting: {}", peer.addr, e);
+                            self.peers.remove(peer_id);
+                            let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
+                            self.peer_list.swap_remove(index);
+                            break;
+                        }
+                    }
+                }
+                Ok(ReadResult::Oversized(length)) => {
+                    self._handle.metrics().oversized_frames += 1;
+                    warn!("Peer {} sent a frame of {} bytes, more than {}, disconnecting", peer.addr, length, self.limits.max_frame_size);
+                    self.peers.remove(peer_id);
+                    let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
+                    self.peer_list.swap_remove(index);
+                    break;
+                }
                 Err(e) => {
                     if e.kind() == std::io::ErrorKind::WouldBlock {
                         trace!("Peer {} finished reading", peer_id);
@@ -205,6 +323,10 @@ impl Context {
                 }
             }
         }
+        // messages queued during the handshake can be sent now
+        if keyed && self.peers.contains(peer_id) {
+            self.register_write_interest(peer_id)?;
+        }
         Ok(())
     }
 
@@ -294,7 +416,8 @@ impl Context {
         let mut events = mio::Events::with_capacity(MAX_EVENT);
 
         loop {
-            self.poll.poll(&mut events, None)?;
+            self.poll.poll(&mut events, Some(HANDSHAKE_CHECK_INTERVAL))?;
+            self.drop_stalled_handshakes();
 
             for event in events.iter() {
                 match event.token() {
@@ -377,9 +500,14 @@ impl Context {
 #[derive(Clone)]
 pub struct Handle {
     control_chan: channel::Sender<ControlSignal>,
+    metrics: Arc<Mutex<Metrics>>,
 }
 
 impl Handle {
+    pub fn metrics(&self) -> MutexGuard<Metrics> {
+        self.metrics.lock().unwrap()
+    }
+
     pub fn connect(&self, addr: std::net::SocketAddr) -> std::io::Result<peer::Handle> {
         let (sender, receiver) = cbchannel::unbounded();
         let request = ConnectRequest {
@@ -397,11 +525,28 @@ impl Handle {
             .send(ControlSignal::BroadcastMessage(msg))
             .unwrap();
     }
+
+    /// Addresses and directions of the connected peers
+    pub fn peers(&self) -> Vec<(std::net::SocketAddr, peer::Direction)> {
+        let (sender, receiver) = cbchannel::unbounded();
+        self.control_chan
+            .send(ControlSignal::ListPeers(sender))
+            .unwrap();
+        receiver.recv().unwrap()
+    }
+
+    pub fn disconnect(&self, addr: std::net::SocketAddr) {
+        self.control_chan
+            .send(ControlSignal::DisconnectPeer(addr))
+            .unwrap();
+    }
 }
 
 enum ControlSignal {
     ConnectNewPeer(ConnectRequest),
     BroadcastMessage(message::Message),
+    ListPeers(cbchannel::Sender<Vec<(std::net::SocketAddr, peer::Direction)>>),
+    DisconnectPeer(std::net::SocketAddr),
 }
 
 struct ConnectRequest {
@@ -1,4 +1,9 @@
-use super::message::Message;
+use super::addrman::{self, AddrManager};
+use super::banman::{self, BanManager};
+use super::compact::{CompactBlock, PartialBlock};
+use super::download::{self, DownloadManager};
+use super::inventory::{self, InventorySet};
+use super::message::{self, Message, Version, MAX_HEADERS};
 use super::peer;
 use crate::network::server::Handle as ServerHandle;
 use crossbeam::channel;
@@ -11,10 +16,11 @@ use crate::block::{Block,Header,Content};
 use crate::crypto::merkle::{MerkleTree};
 use crate::transaction::{Mempool, StateWitness, SignedTransaction};
 use crate::accumulator::Accumulator;
+use crate::timedata::{self, TimeData};
 use ring::signature::{Ed25519KeyPair, Signature, KeyPair, VerificationAlgorithm, EdDSAParameters};
 
 use std::collections::HashMap;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::net::SocketAddr;
 
 use log::info;
 
@@ -22,9 +28,19 @@ use log::info;
 
 use std::{thread, time};
 use crate::transaction;
+use crate::reorg;
+use crate::script::Script;
+use crate::validation::{self, BlockError, TxError};
 use std::ascii::escape_default;
 use std::hash::Hash;
 
+// how far ahead of the tip blocks of the best header chain are downloaded
+const DOWNLOAD_WINDOW: usize = 128;
+// compact blocks waiting for their missing transactions at the same time
+const MAX_PENDING_COMPACT: usize = 32;
+// headers kept for a later try after failing a check that may pass in time
+const MAX_DEFERRED_HEADERS: usize = MAX_HEADERS;
+
 #[derive(Debug, Clone)]
 pub struct OrphanBuffer {
     //Using HashMap for orphan_buffer
@@ -78,6 +94,14 @@ pub struct Context {
     num_worker: usize,
     server: ServerHandle,
     accumulator: Arc<Mutex<Accumulator>>,
+    timedata: Arc<Mutex<TimeData>>,
+    download: Arc<Mutex<DownloadManager>>,
+    addrman: Arc<Mutex<AddrManager>>,
+    banman: Arc<Mutex<BanManager>>,
+    seen_witnesses: Arc<Mutex<InventorySet>>,
+    pending_compact: Arc<Mutex<HashMap<H256, (SocketAddr, PartialBlock, time::Instant)>>>, // Block Hash <-> (Peer asked, Block, Time asked)
+    deferred_headers: Arc<Mutex<Vec<Header>>>, // in chain order, such as headers with a timestamp too far in the future
+    listen_port: u16,
     ifArchival: bool,
 }
 
@@ -92,6 +116,10 @@ pub fn new(
     msg_src: channel::Receiver<(Vec<u8>, peer::Handle)>,
     server: &ServerHandle,
     accumulator:&Arc<Mutex<Accumulator>>,
+    timedata: &Arc<Mutex<TimeData>>,
+    addrman: &Arc<Mutex<AddrManager>>,
+    banman: &Arc<Mutex<BanManager>>,
+    listen_port: u16,
     ifArchival: bool
 ) -> Context {
     Context {
@@ -107,11 +135,217 @@ pub fn new(
         num_worker,
         server: server.clone(),
         accumulator: Arc::clone(accumulator),
+        timedata: Arc::clone(timedata),
+        download: Arc::new(Mutex::new(DownloadManager::new(download::MAX_IN_FLIGHT_PER_PEER, download::REQUEST_TIMEOUT))),
+        addrman: Arc::clone(addrman),
+        banman: Arc::clone(banman),
+        seen_witnesses: Arc::new(Mutex::new(InventorySet::new(inventory::MAX_SEEN_WITNESSES))),
+        pending_compact: Arc::new(Mutex::new(HashMap::new())),
+        deferred_headers: Arc::new(Mutex::new(Vec::new())),
+        listen_port,
         ifArchival: ifArchival,
     }
 }
 
 impl Context {
+    /// Bring the mempool and the state in line with the blockchain after its tip moved away from `old_tip`.
+    /// The archival node rebuilds its accumulator proof for the new tip and broadcasts the new witnesses.
+    fn update_tip(&self, blockchain: &mut Blockchain, old_tip: &H256) {
+        let mut mempool = self.mempool.lock().unwrap();
+        let mut stateWitness = self.stateWitness.lock().unwrap();
+        if !self.ifArchival {
+            reorg::update_tip(blockchain, old_tip, &mut mempool, &mut stateWitness, None);
+            return;
+        }
+        let mut accumulator = self.accumulator.lock().unwrap();
+        let change = reorg::update_tip(blockchain, old_tip, &mut mempool, &mut stateWitness, Some(&mut accumulator));
+        if change.connected.is_empty() {
+            return;
+        }
+        self.server.broadcast(Message::NewStateWitness(stateWitness.getAllStates(),stateWitness.getNewProof(&blockchain.tip())));
+        for state_key in stateWitness.States.keys(){
+            let recp = &stateWitness.States.get(state_key).unwrap().1;
+            let value = stateWitness.States.get(state_key).unwrap().0;
+            println!("ARCHIVAL NODE: UPDATED STATE WITNESS: RCEP: {:?}, VALUE: {:?}", recp, value);
+        }
+    }
+
+    /// Validate a block whose parent may be unknown and, if it passes, insert it and follow the new tip
+    fn process_block(&self, blockchain: &mut Blockchain, block: &Block, now: u128) -> Result<(), BlockError> {
+        let stateWitness = self.stateWitness.lock().unwrap();
+        let result = validation::check_block(block, blockchain, &stateWitness, self.ifArchival, now);
+        std::mem::drop(stateWitness);
+        result?;
+        let old_tip = blockchain.tip();
+        blockchain.insert(block);
+        self.update_tip(blockchain, &old_tip);
+        Ok(())
+    }
+
+    /// Add `score` to the misbehavior of a peer, and disconnect and ban it once it crosses the threshold
+    fn misbehaving(&self, peer: &peer::Handle, score: u32, reason: &str) {
+        let total = {
+            let mut status = peer.status();
+            status.misbehavior += score;
+            status.misbehavior
+        };
+        warn!("Peer {} misbehaved: {}, score {}", peer.addr(), reason, total);
+        if total >= banman::BAN_THRESHOLD {
+            warn!("Banning peer {}", peer.addr());
+            self.banman.lock().unwrap().ban_peer(peer.listen_addr(), timedata::local_time(), reason);
+            self.download.lock().unwrap().remove_peer(&peer.addr());
+            self.server.disconnect(peer.addr());
+        }
+    }
+
+    /// Start syncing from a peer once both sides accepted each other's version
+    fn handshake_done(&self, peer: &peer::Handle) {
+        let status = peer.status();
+        let version = status.version.as_ref().unwrap();
+        info!("Handshake with {} done: protocol version {}, height {}, {}{}", peer.addr(), version.protocol_version,
+              version.best_height, if version.archival { "archival" } else { "stateless" },
+              status.node_address.map_or(String::new(), |address| format!(", node address {:?}", address)));
+        std::mem::drop(status);
+        let listen_addr = peer.listen_addr();
+        let now = timedata::local_time();
+        match peer.direction() {
+            peer::Direction::Outgoing => {
+                // only ask the peers we chose for addresses, and take no others, so that connecting to us cannot
+                // fill our address book
+                self.addrman.lock().unwrap().good(&peer.addr(), now);
+                peer.status().addr_requested = true;
+                peer.write(Message::GetAddr);
+            }
+            peer::Direction::Incoming => {
+                self.addrman.lock().unwrap().add(listen_addr, now);
+            }
+        }
+        peer.write(Message::GetHeaders(self.blockchain.lock().unwrap().locator()));
+    }
+
+    /// Validate and insert blocks received from a peer, along with the orphans they complete, and announce the
+    /// new tip
+    fn receive_blocks(&self, peer: &peer::Handle, blocks: &[Block]) {
+        let mut blockchain = self.blockchain.lock().unwrap();
+        let mut orphanbuffer = self.orphanbuffer.lock().unwrap();
+        let now = self.timedata.lock().unwrap().adjusted_time();
+        let old_tip = blockchain.tip();
+
+        let mut newlyOrphanParent = Vec::<H256>::new();
+        let mut newlyProcessedBlockHashes = Vec::<H256>::new();
+
+        let mut penalty = None;
+        let mut download = self.download.lock().unwrap();
+        let mut pending_compact = self.pending_compact.lock().unwrap();
+        for block in blocks.iter() {
+            download.received(&block.hash());
+            pending_compact.remove(&block.hash());
+        }
+        std::mem::drop(pending_compact);
+        std::mem::drop(download);
+
+        for block in blocks.iter() {
+            //info!("WORKER: RECEIVING BLOCKS...");
+            if blockchain.Blocks.contains_key(&block.hash()) {
+                continue;
+            }
+            match self.process_block(&mut blockchain, block, now) {
+                Ok(()) => {
+                    info!("WORKER: BLOCKS RECEIVED FROM THE OTHER SENDER");
+                    println!("WORKER: CURRENT BLOCKCHAIN HEIGHT: {:?}", blockchain.tip.1);
+                    newlyProcessedBlockHashes.push(block.hash());
+                }
+                Err(BlockError::MissingParent(parent)) => {// orphan blocks created only when blocks were not inserted.
+                    // Insert orphan blocks into buffer
+                    let mut newlyOrphans = Vec::<Block>::new();
+                    if orphanbuffer.isParentIn(&parent){
+                        newlyOrphans = orphanbuffer.getOrphanBlocks(&parent);
+                    }
+                    newlyOrphans.push(block.clone());
+                    orphanbuffer.insert(parent,newlyOrphans);
+                    //println!("orphan inserted: {:?}", block.getparent());
+                    newlyOrphanParent.push(parent);
+                }
+                Err(e) => {
+                    warn!("WORKER: REJECTED BLOCK {:?} FROM {}: {}", block.hash(), peer.addr(), e);
+                    if let Some(score) = block_misbehavior(&e) {
+                        // no peer can serve a valid body for this header, unless this body was tampered with
+                        // and does not match the header's merkle root
+                        match e {
+                            BlockError::BadMerkleRoot | BlockError::DuplicateTransaction(_) | BlockError::Empty => {}
+                            _ => blockchain.invalidate_header(&block.hash()),
+                        }
+                        penalty = Some((score, format!("invalid block {:?}: {}", block.hash(), e)));
+                        break;
+                    }
+                }
+            }
+        }
+        if newlyOrphanParent.len() != 0 {
+            peer.write(Message::GetHeaders(blockchain.locator())); //Ask for the headers leading to the orphans, their blocks are requested next
+        }
+        // println!("orphan buffer length: {:?}", orphanbuffer.HashMap.len());
+        // Orphan Handler: every newly processed block may be the parent of buffered orphans,
+        // and every orphan processed that way may in turn be the parent of more orphans
+        let mut idx = 0;
+        while idx < newlyProcessedBlockHashes.len() {
+            let parent = newlyProcessedBlockHashes[idx];
+            idx += 1;
+            if !orphanbuffer.isParentIn(&parent) {
+                continue;
+            }
+            let orphans = orphanbuffer.getOrphanBlocks(&parent);
+            orphanbuffer.remove(&parent);
+            for orphan in orphans {
+                match self.process_block(&mut blockchain, &orphan, now) {
+                    Ok(()) => {
+                        info!("WORKER: ORPHAN BLOCKS RECEIVED");
+                        newlyProcessedBlockHashes.push(orphan.hash());
+                    }
+                    Err(e) => {
+                        warn!("WORKER: REJECTED ORPHAN BLOCK {:?}: {}", orphan.hash(), e);
+                    }
+                }
+            }
+        }
+        // keep downloading the best header chain, and announce the new tip
+        let new_tip = blockchain.tip();
+        std::mem::drop(orphanbuffer);
+        std::mem::drop(blockchain);
+        if newlyProcessedBlockHashes.capacity()>0 {
+            println!("WORKER: NEWLY PROCESSED BLOCK HASHES: {:?}",newlyProcessedBlockHashes);
+        }
+        if let Some((score, reason)) = penalty {
+            self.misbehaving(peer, score, &reason);
+        }
+        self.request_blocks();
+        if new_tip != old_tip {
+            let block = self.blockchain.lock().unwrap().Blocks.get(&new_tip).unwrap().0.clone();
+            self.server.broadcast(Message::CompactBlock(CompactBlock::new(&block)));
+        }
+    }
+
+    /// Fall back to downloading the whole block from the peer that announced it in compact form
+    fn request_full_block(&self, peer: &peer::Handle, hash: H256) {
+        if self.download.lock().unwrap().request_from(peer.addr(), hash, time::Instant::now()) {
+            peer.write(Message::GetBlocks(vec![hash]));
+        }
+    }
+
+    /// Spread the missing blocks of the best header chain over the peers we sync from, after re-issuing the
+    /// requests of peers that stalled
+    fn request_blocks(&self) {
+        let wanted = self.blockchain.lock().unwrap().missing_blocks(DOWNLOAD_WINDOW);
+        let mut download = self.download.lock().unwrap();
+        let now = time::Instant::now();
+        for addr in download.expire(now) {
+            warn!("Block download from {} stalled, requesting its blocks elsewhere", addr);
+        }
+        for (addr, hashes) in download.assign(&wanted, now) {
+            download.handle(&addr).unwrap().write(Message::GetBlocks(hashes));
+        }
+    }
+
     pub fn start(self) {
         let num_worker = self.num_worker;
         for i in 0..num_worker {
@@ -121,6 +355,14 @@ impl Context {
                 warn!("Worker thread {} exited", i);
             });
         }
+        // stalled block requests are only noticed by polling
+        let cloned = self.clone();
+        thread::spawn(move || {
+            loop {
+                thread::sleep(time::Duration::from_secs(1));
+                cloned.request_blocks();
+            }
+        });
     }
 
 
@@ -129,7 +371,44 @@ impl Context {
         loop {
             let msg = self.msg_chan.recv().unwrap();
             let (msg, peer) = msg;
-            let msg: Message = bincode::deserialize(&msg).unwrap();
+            let banman = self.banman.lock().unwrap();
+            let now = timedata::local_time();
+            let banned = banman.is_banned(&peer.addr(), now) || banman.is_banned(&peer.listen_addr(), now);
+            std::mem::drop(banman);
+            if banned {
+                self.server.disconnect(peer.addr());
+                continue;
+            }
+            let size = msg.len();
+            let msg: Message = match bincode::deserialize(&msg) {
+                Ok(msg) => msg,
+                Err(e) if peer.status().version.is_none() => {
+                    // most likely a peer using the other transport, which is a configuration mistake, not misbehavior
+                    warn!("Peer {} sent an undecodable first message, disconnecting: {}", peer.addr(), e);
+                    self.server.disconnect(peer.addr());
+                    continue;
+                }
+                Err(e) => {
+                    self.misbehaving(&peer, banman::UNDECODABLE_MESSAGE, &format!("undecodable message: {}", e));
+                    continue;
+                }
+            };
+            let mut metrics = self.server.metrics();
+            *metrics.received_by_type.entry(msg.name().to_string()).or_insert(0) += 1;
+            if msg.size_limit().map_or(false, |limit| size > limit) {
+                metrics.oversized_messages += 1;
+                std::mem::drop(metrics);
+                warn!("Peer {} sent a {} message of {} bytes, disconnecting", peer.addr(), msg.name(), size);
+                self.server.disconnect(peer.addr());
+                continue;
+            }
+            std::mem::drop(metrics);
+            // nothing but the handshake is processed until we accepted the peer's version and it acknowledged ours
+            if !peer.accepts(&msg) {
+                warn!("Peer {} sent a message before the handshake, disconnecting", peer.addr());
+                self.server.disconnect(peer.addr());
+                continue;
+            }
             match msg {
                 Message::Ping(nonce) => {
                     debug!("Ping: {}", nonce);
@@ -138,25 +417,148 @@ impl Context {
                 Message::Pong(nonce) => {
                     debug!("Pong: {}", nonce);
                 }
+                Message::Version(version) => {
+                    if peer.status().version.is_some() {
+                        self.misbehaving(&peer, banman::PROTOCOL_VIOLATION, "sent its version twice");
+                        continue;
+                    }
+                    let local = Version::new(&self.blockchain.lock().unwrap(), self.ifArchival, self.listen_port);
+                    if let Some(reason) = version.incompatibility(&local) {
+                        warn!("Peer {} failed the handshake: {}, disconnecting", peer.addr(), reason);
+                        self.server.disconnect(peer.addr());
+                        continue;
+                    }
+                    self.timedata.lock().unwrap().add_sample(peer.addr().ip(), version.time);
+                    let done = {
+                        let mut status = peer.status();
+                        status.version = Some(version);
+                        status.verack_received
+                    };
+                    if let peer::Direction::Incoming = peer.direction() {
+                        peer.write(Message::Version(local));
+                    }
+                    peer.write(Message::Verack);
+                    if done {
+                        self.handshake_done(&peer);
+                    }
+                }
+                Message::Verack => {
+                    let done = {
+                        let mut status = peer.status();
+                        let first = !status.verack_received;
+                        status.verack_received = true;
+                        first && status.version.is_some()
+                    };
+                    if done {
+                        self.handshake_done(&peer);
+                    }
+                }
+                Message::GetAddr => {
+                    let addrs = self.addrman.lock().unwrap().sample(addrman::MAX_ADDR_PER_MESSAGE);
+                    if !addrs.is_empty() {
+                        peer.write(Message::Addr(addrs));
+                    }
+                }
+                Message::Addr(addrs) => {
+                    if addrs.len() > addrman::MAX_ADDR_PER_MESSAGE {
+                        self.misbehaving(&peer, banman::PROTOCOL_VIOLATION, &format!("sent {} addresses", addrs.len()));
+                        continue;
+                    }
+                    if !peer.answers_addr_request() {
+                        self.misbehaving(&peer, banman::UNSOLICITED, "sent unsolicited addresses");
+                        continue;
+                    }
+                    // gossiped addresses count as seen now
+                    let now = timedata::local_time();
+                    let mut addrman = self.addrman.lock().unwrap();
+                    let new = addrs.into_iter().filter(|addr| addrman.add(*addr, now)).count();
+                    debug!("Learned {} new addresses from {}", new, peer.addr());
+                }
                 Message::NewBlockHashes(hashes) => {
+                    peer.mark_known(&hashes);
                     //debug!("NewBlockHashes: {:?}", hashes);
-                    //self.server.broadcast(Message::NewBlockHashes(hashes.clone()));
                     //info!("WORKER: RECEIVED BLOCK MESSAGES");
-                    let mut notContainedHashes = Vec::<H256>::new();
+                    // a new tip whose header we do not know: ask for the headers leading to it
+                    let blockchain = self.blockchain.lock().unwrap();
+                    if hashes.iter().any(|hash| !blockchain.Headers.contains_key(hash)) {
+                        peer.write(Message::GetHeaders(blockchain.locator()));
+                    }
+                }
+
+                Message::GetHeaders(locator) => {
+                    let blockchain = self.blockchain.lock().unwrap();
+                    let headers = blockchain.headers_after(&locator, MAX_HEADERS);
+                    std::mem::drop(blockchain);
+                    if !headers.is_empty() {
+                        peer.write(Message::Headers(headers));
+                    }
+                }
+
+                Message::Headers(headers) => {
+                    peer.mark_known(&headers.iter().map(|header| header.hash()).collect::<Vec<H256>>());
                     let mut blockchain = self.blockchain.lock().unwrap();
-                    let mut orphanbuffer = self.orphanbuffer.lock().unwrap();
-                    if hashes.len() != 0 {
-                        for hash in hashes.iter() {
-                            if blockchain.Blocks.get(&hash).is_none() {
-                                notContainedHashes.push(*hash);
+                    let now = self.timedata.lock().unwrap().adjusted_time();
+                    let mut deferred_headers = self.deferred_headers.lock().unwrap();
+                    // retry the headers deferred earlier; those that fail for good are dropped
+                    let retry = std::mem::replace(&mut *deferred_headers, Vec::new());
+                    for header in retry {
+                        if blockchain.Headers.contains_key(&header.hash()) {
+                            continue;
+                        }
+                        match validation::check_header(&header, &blockchain, now) {
+                            Ok(()) => {
+                                blockchain.insert_header(&header);
                             }
+                            Err(e) if !e.is_permanent() => deferred_headers.push(header),
+                            Err(_) => {}
                         }
                     }
-                    if notContainedHashes.len() != 0 {
-                        peer.write(Message::GetBlocks(notContainedHashes));
+                    let mut connected = true;
+                    let mut deferred = false;
+                    let mut penalty = None;
+                    for (index, header) in headers.iter().enumerate() {
+                        if blockchain.Headers.contains_key(&header.hash()) {
+                            continue;
+                        }
+                        match validation::check_header(header, &blockchain, now) {
+                            Ok(()) => {
+                                blockchain.insert_header(header);
+                            }
+                            Err(BlockError::MissingParent(_)) => {
+                                // the headers do not connect to ours, start over from our locator
+                                connected = false;
+                                break;
+                            }
+                            Err(e) => {
+                                warn!("WORKER: REJECTED HEADER {:?} FROM {}: {}", header.hash(), peer.addr(), e);
+                                penalty = block_misbehavior(&e).map(|score| (score, format!("invalid header: {}", e)));
+                                if penalty.is_none() {
+                                    // the peer would only send the same headers again: keep them and try later
+                                    deferred = true;
+                                    for header in headers[index..].iter() {
+                                        if !deferred_headers.iter().any(|kept| kept.hash() == header.hash()) {
+                                            deferred_headers.push(header.clone());
+                                        }
+                                    }
+                                    deferred_headers.truncate(MAX_DEFERRED_HEADERS);
+                                }
+                                break;
+                            }
+                        }
                     }
-
-
+                    std::mem::drop(deferred_headers);
+                    std::mem::drop(blockchain);
+                    if let Some((score, reason)) = penalty {
+                        self.misbehaving(&peer, score, &reason);
+                        continue;
+                    }
+                    // a full message means the peer has more headers for us
+                    if !connected || (headers.len() == MAX_HEADERS && !deferred) {
+                        peer.write(Message::GetHeaders(self.blockchain.lock().unwrap().locator()));
+                    }
+                    // the peer has the blocks of these headers
+                    self.download.lock().unwrap().add_peer(&peer);
+                    self.request_blocks();
                 }
 
                 Message::GetBlocks(hashes) => {
@@ -174,216 +576,138 @@ impl Context {
                         }
                     }
                     if notContainedBlocks.len() != 0 {
+                        peer.mark_known(&notContainedBlocks.iter().map(|block| block.hash()).collect::<Vec<H256>>());
                         peer.write(Message::Blocks(notContainedBlocks));
                     }
 
                 }
 
                 Message::Blocks(blocks) => {
+                    peer.mark_known(&blocks.iter().map(|block| block.hash()).collect::<Vec<H256>>());
                     //debug!("Blocks: {:?}", blocks);
                     //info!("WORKER: START RECEIVING BLOCKS");
-                    let mut blocks = blocks.clone();
-                    let mut blockchain = self.blockchain.lock().unwrap();
-                    let mut orphanbuffer = self.orphanbuffer.lock().unwrap();
-
-                    let mut newlyOrphanParent = Vec::<H256>::new();
-                    let mut newlyProcessedBlockHashes = Vec::<H256>::new();
-
-                    for block in blocks.iter() {
-                        //info!("WORKER: RECEIVING BLOCKS...");
-                        //PoW check
-                        let difficulty = blockchain.Blocks.get(&blockchain.tip.0).unwrap().0.getdifficulty();
-                        if block.hash() <=  difficulty{
-                            //info!("WORKER: DIFFICULTY CHECK1 SUCCESS");
-                            if block.Header.difficulty == difficulty{
-                                //info!("WORKER: DIFFICULTY CHECK2 SUCCESS");
-                                if blockchain.Blocks.contains_key(&block.getparent()){
-                                    //info!("WORKER: PARENT CHECK SUCCESS");
-                                    //println!("block parent: {:?}", block.getparent());
-                                    //println!("WORKER: PRESENT TIP {:?}", blockchain.tip.0);
-                                    //double spend check & signature check
-                                    let mut contents = block.Content.content.clone();
-                                    //let mut state = self.state.lock().unwrap();
-                                    let mut stateWitness = self.stateWitness.lock().unwrap();
-                                    let mut check = true;
-                                    //println!("WORKER: CONTENTS: {:?}", contents);
-
-                                    for content in contents.iter(){
-                                        //println!("WORKER: CONTENT INPUT: {:?}", content.transaction.Input,blockchain.tip.0);
-                                        //println!("verify: {:?}",content.verifySignedTransaction());
-                                        //println!("double: {:?}",stateWitness.ifNotDoubleSpent(&content.transaction.Input, &blockchain.tip.0));
-                                        if stateWitness.ifNotDoubleSpent(&content.transaction.Input, &blockchain.tip.0)
-                                            && content.verifySignedTransaction() { //TODO BATCH VERIFICATION
-                                            check = check && true;
-                                        } else {
-                                            check = check && false;
-                                            break;
-                                        }
-                                    }
-
-                                    std::mem::drop(stateWitness);
-                                    println!("WORKER CHECK: {:?}",check);
-
-
-                                    if check{
-                                        //info!("WORKER:INSERTING NEW BLOCKS...");
-                                        let tip_hash = blockchain.insert(&block);
-
-                                        info!("WORKER: BLOCKS RECEIVED FROM THE OTHER SENDER");
-                                        println!("WORKER: CURRENT BLOCKCHAIN HEIGHT: {:?}", blockchain.tip.1);
-                                        // info!("Worker: Blocks mined by one can be received by the other.");
-                                        //let mut stateWitness = self.stateWitness.lock().unwrap();
-                                        let mut mempool = self.mempool.lock().unwrap();
-                                        mempool.updateMempool(&contents);
-                                        std::mem::drop(mempool);
-                                        /*for key in state.Outputs.keys() {
-                                            println!("WORKER: RECP: {:?}, VALUE {:?} BTC", state.Outputs.get(key).unwrap().1, state.Outputs.get(key).unwrap().0);
-                                        }*/
-                                        newlyProcessedBlockHashes.push(block.hash());
-
-
-                                        if self.ifArchival { //TODO
-                                            //info!("WORKER 1");
-                                            //update state witnesses and broadcast state witnesses with accumulator proof
-                                            let mut stateWitness = self.stateWitness.lock().unwrap();
-                                            let mut accumulator = self.accumulator.lock().unwrap();
-                                            for content in contents.iter(){
-                                                for input in content.transaction.Input.clone(){
-                                                    //info!("WORKER 2");
-                                                    stateWitness.deleteStates(input.prevTransaction, input.preOutputIndex);
-                                                    accumulator.delete_hash_prime(input.prevTransaction,input.preOutputIndex, input.witness.prime_number);
-                                                }
-                                                for output in content.transaction.Output.clone(){
-                                                    //Add states to accumulator
-                                                    //info!("WORKER 3");
-                                                    accumulator.hash_to_prime(content.hash(), output.index, output.value,output.recpAddress);
-                                                }
-                                            }
-                                            //info!("WORKER 4");
-                                            accumulator.update_parameters();
-                                            //Calculate accumulator proof and Add it to Accumulator Proof
-
-                                            let A = accumulator.accumulate();
-                                            stateWitness.AccumulatorProof.insert(block.hash(),A);
-                                            //Calculate witnesses and Add states with witnesses to stateWitness
-                                            for (key, values) in accumulator.accumulator.iter() {
-                                                //let witness = A / ((accumulator.g).pow(values.2));
-                                                let mut witness = A / (values.2 as u128);
-                                                stateWitness.addStates(key.0, key.1, values.0,values.1, values.2, witness );
-                                            }
-                                            self.server.broadcast(Message::NewStateWitness(stateWitness.getAllStates(),stateWitness.getNewProof(&block.hash())));
-                                            for state_key in stateWitness.States.keys(){
-                                                let recp = stateWitness.States.get(state_key).unwrap().1;
-                                                let value = stateWitness.States.get(state_key).unwrap().0;
-                                                println!("ARCHIVAL NODE: UPDATED STATE WITNESS: RCEP: {:?}, VALUE: {:?}", recp, value);
-                                            }
-                                            //println!("ARCHIVAL NODE: UPDATED STATE WITNESS{:?}", stateWitness);
-                                            //info!("WORKER 5");
-                                            std::mem::drop(stateWitness);
-                                            std::mem::drop(accumulator);
-
-                                        }
-
-                                    }
-
-
-                                }
-                                else{// orphan blocks created only when blocks were not inserted.
-                                    // Insert orphan blocks into buffer
-                                    let mut newlyOrphans = Vec::<Block>::new();
-                                    if orphanbuffer.isParentIn(&block.getparent()){
-                                        newlyOrphans = orphanbuffer.getOrphanBlocks(&block.getparent());
-                                    }
-                                    newlyOrphans.push(block.clone());
-                                    orphanbuffer.insert(block.getparent(),newlyOrphans);
-                                    //println!("orphan inserted: {:?}", block.getparent());
-                                    newlyOrphanParent.push(block.getparent());
-                                }
-                            }
-                        }
+                    // blocks are only sent when asked for, although an answer may come after we asked another peer
+                    let blockchain = self.blockchain.lock().unwrap();
+                    let download = self.download.lock().unwrap();
+                    let unsolicited = blocks
+                        .iter()
+                        .map(|block| block.hash())
+                        .find(|hash| !download.is_in_flight(hash) && !blockchain.Blocks.contains_key(hash));
+                    std::mem::drop(download);
+                    std::mem::drop(blockchain);
+                    if let Some(hash) = unsolicited {
+                        self.misbehaving(&peer, banman::UNSOLICITED, &format!("sent unsolicited block {:?}", hash));
                     }
-                    peer.write(Message::GetBlocks(newlyOrphanParent)); //Send GetBlocks messages for getting parent blocks of orphans
-                    // println!("orphan buffer length: {:?}", orphanbuffer.HashMap.len());
-                    // Orphan Handler
-                    for idx in 0..newlyProcessedBlockHashes.len(){
-                        if orphanbuffer.isParentIn(&newlyProcessedBlockHashes[idx]){
-                            let orphans = orphanbuffer.getOrphanBlocks(&newlyProcessedBlockHashes[idx]);
-                            for orphan in orphans{
-                                let mut contents = orphan.Content.content.clone();
-                                //TODO
-                                let mut stateWitness = self.stateWitness.lock().unwrap();
-                                let mut mempool = self.mempool.lock().unwrap();
-                                let mut check = true;
-                                for content in contents.iter(){
-                                    if stateWitness.ifNotDoubleSpent(&content.transaction.Input, &blockchain.tip.0) && content.verifySignedTransaction() { //TODO
-                                        check = check && true;
-                                    }
-                                    else{
-                                        check = check && false;
-                                        break;
-                                    }
-                                }
-                                std::mem::drop(mempool);
-                                if check {
-                                    blockchain.insert(&orphan);
-                                    //let mut state = self.state.lock().unwrap();
-                                    let mut mempool = self.mempool.lock().unwrap();
-
-                                    mempool.updateMempool(&contents);
-
-                                    info!("WORKER: ORPHAN BLOCKS RECEIVED");
-                                    //std::mem::drop(state);
-                                    std::mem::drop(mempool);
-
-                                    if self.ifArchival { //TODO
-                                        //update state witnesses and broadcast state witnesses with accumulator proof
-                                        let mut stateWitness = self.stateWitness.lock().unwrap();
-                                        let mut accumulator = self.accumulator.lock().unwrap();
-                                        for content in contents.iter(){
-                                            for input in content.transaction.Input.clone(){
-                                                stateWitness.deleteStates(input.prevTransaction, input.preOutputIndex);
-                                                accumulator.delete_hash_prime(input.prevTransaction,input.preOutputIndex, input.witness.prime_number);
-                                            }
-                                            for output in content.transaction.Output.clone(){
-                                                //Add states to accumulator
-                                                accumulator.hash_to_prime(content.hash(), output.index, output.value,output.recpAddress);
-                                            }
-                                        }
-                                        accumulator.update_parameters();
-                                        //Calculate accumulator proof and Add it to Accumulator Proof
-                                        let A = accumulator.accumulate();
-                                        stateWitness.AccumulatorProof.insert(orphan.hash(),A);
-                                        //Calculate witnesses and Add states with witnesses to stateWitness
-                                        for (key, values) in accumulator.accumulator.iter() {
-                                            //let witness = A / ((accumulator.g).pow(values.2));
-                                            let mut witness = A / (values.2 as u128);
-                                            stateWitness.addStates(key.0, key.1, values.0,values.1, values.2, witness );
-                                        }
-                                        println!("ARCHIVAL NODE: UPDATED STATE WITNESS{:?}", stateWitness);
-                                        self.server.broadcast(Message::NewStateWitness(stateWitness.getAllStates(),stateWitness.getNewProof(&orphan.hash())));
-                                        std::mem::drop(stateWitness);
-                                        std::mem::drop(accumulator);
+                    self.receive_blocks(&peer, &blocks);
+                }
 
-                                    }
 
+                Message::CompactBlock(compact) => {
+                    let hash = compact.hash();
+                    peer.mark_known(&[hash]);
+                    let mut blockchain = self.blockchain.lock().unwrap();
+                    if blockchain.Blocks.contains_key(&hash) || self.pending_compact.lock().unwrap().contains_key(&hash) {
+                        continue;
+                    }
+                    if !blockchain.Headers.contains_key(&hash) {
+                        let now = self.timedata.lock().unwrap().adjusted_time();
+                        match validation::check_header(&compact.header, &blockchain, now) {
+                            Ok(()) => {
+                                blockchain.insert_header(&compact.header);
+                            }
+                            Err(BlockError::MissingParent(_)) => {
+                                // we are behind this peer, catch up through its headers first
+                                peer.write(Message::GetHeaders(blockchain.locator()));
+                                continue;
+                            }
+                            Err(e) => {
+                                std::mem::drop(blockchain);
+                                warn!("WORKER: REJECTED COMPACT BLOCK {:?} FROM {}: {}", hash, peer.addr(), e);
+                                if let Some(score) = block_misbehavior(&e) {
+                                    self.misbehaving(&peer, score, &format!("invalid header: {}", e));
                                 }
-
-
+                                continue;
                             }
-                            orphanbuffer.remove(&newlyProcessedBlockHashes[idx]);
                         }
                     }
+                    let partial = PartialBlock::new(&compact, &self.mempool.lock().unwrap());
                     std::mem::drop(blockchain);
-                    if newlyProcessedBlockHashes.capacity()>0 {
-                        println!("WORKER: NEWLY PROCESSED BLOCK HASHES: {:?}",newlyProcessedBlockHashes);
-                        self.server.broadcast(Message::NewBlockHashes(newlyProcessedBlockHashes));
+                    let partial = match partial {
+                        Ok(partial) => partial,
+                        Err(e) => {
+                            self.misbehaving(&peer, banman::INVALID_DATA, &format!("malformed compact block {:?}: {}", hash, e));
+                            continue;
+                        }
+                    };
+                    let missing = partial.missing();
+                    if missing.is_empty() {
+                        match partial.block() {
+                            Some(block) => self.receive_blocks(&peer, &[block]),
+                            // short ids of the block collide with other transactions of our mempool
+                            None => self.request_full_block(&peer, hash),
+                        }
+                        continue;
                     }
-                    // println!("Current height of blockchain: {:?}", blockchain.tip.1);
+                    let now = time::Instant::now();
+                    let mut pending_compact = self.pending_compact.lock().unwrap();
+                    pending_compact.retain(|_, (_, _, asked)| now.duration_since(*asked) < download::REQUEST_TIMEOUT);
+                    if pending_compact.len() >= MAX_PENDING_COMPACT {
+                        std::mem::drop(pending_compact);
+                        self.request_full_block(&peer, hash);
+                        continue;
+                    }
+                    pending_compact.insert(hash, (peer.addr(), partial, now));
+                    std::mem::drop(pending_compact);
+                    peer.write(Message::GetBlockTxn(hash, missing));
+                }
 
+                Message::GetBlockTxn(hash, indexes) => {
+                    let blockchain = self.blockchain.lock().unwrap();
+                    let block = match blockchain.Blocks.get(&hash) {
+                        Some((block, _)) => block,
+                        None => continue,
+                    };
+                    let txs: Option<Vec<SignedTransaction>> =
+                        indexes.iter().map(|index| block.Content.content.get(*index as usize).cloned()).collect();
+                    std::mem::drop(blockchain);
+                    match txs {
+                        Some(txs) => {
+                            peer.mark_known(&txs.iter().map(|tx| tx.hash()).collect::<Vec<H256>>());
+                            peer.write(Message::BlockTxn(hash, txs));
+                        }
+                        None => {
+                            self.misbehaving(&peer, banman::INVALID_DATA, &format!("asked for transactions out of block {:?}", hash));
+                        }
+                    }
                 }
 
+                Message::BlockTxn(hash, txs) => {
+                    let mut pending_compact = self.pending_compact.lock().unwrap();
+                    let asked = pending_compact.get(&hash).map_or(false, |(addr, _, _)| *addr == peer.addr());
+                    if !asked {
+                        std::mem::drop(pending_compact);
+                        // the block may have arrived from another peer meanwhile
+                        if !self.blockchain.lock().unwrap().Blocks.contains_key(&hash) {
+                            self.misbehaving(&peer, banman::UNSOLICITED, &format!("sent unsolicited transactions of block {:?}", hash));
+                        }
+                        continue;
+                    }
+                    let (_, mut partial, _) = pending_compact.remove(&hash).unwrap();
+                    std::mem::drop(pending_compact);
+                    peer.mark_known(&txs.iter().map(|tx| tx.hash()).collect::<Vec<H256>>());
+                    if let Err(e) = partial.fill(txs) {
+                        self.misbehaving(&peer, banman::INVALID_DATA, &format!("bad transactions of block {:?}: {}", hash, e));
+                        continue;
+                    }
+                    match partial.block() {
+                        Some(block) => self.receive_blocks(&peer, &[block]),
+                        // a short id collision in our mempool, or transactions that do not belong to the block
+                        None => self.request_full_block(&peer, hash),
+                    }
+                }
 
                 Message::NewTransactionHashes(hashes) => {
+                    peer.mark_known(&hashes);
                     //println!("newTransactionhashes: {:?}",hashes);
                     //info!("WORKER: NEW TRANSACTION HASHES RECEIVED");
                     let mut mempool = self.mempool.lock().unwrap();
@@ -417,34 +741,64 @@ impl Context {
                     std::mem::drop(mempool);
                     if notContainedTransactions.len() != 0{
                         //info!("WORKER: SENDING TXS");
+                        peer.mark_known(&notContainedTransactions.iter().map(|tx| tx.hash()).collect::<Vec<H256>>());
                         peer.write(Message::Transactions(notContainedTransactions));
                     }
 
                 }
 
                 Message::Transactions(Transactions) => {
+                    peer.mark_known(&Transactions.iter().map(|tx| tx.hash()).collect::<Vec<H256>>());
                     //info!("WORKER: ADDING NEW TRANSACTIONS");
                     //println!("Transactions: {:?}",Transactions);
+                    let blockchain = self.blockchain.lock().unwrap();
+                    let tip = blockchain.tip();
                     let mut mempool = self.mempool.lock().unwrap();
                     let mut stateWitness = self.stateWitness.lock().unwrap();
                     let mut Transactions = Transactions.clone();
                     let mut addedTransactionHashes = Vec::<H256>::new();
+                    let mut penalty = None;
 
                     for Transaction in Transactions.iter(){
                         if !mempool.Transactions.contains_key(&Transaction.hash()) {
                             //Transaction signature check
                             //info!("checking");
                             //println!("verify: {:?}, double: {:?}",Transaction.verifySignedTransaction() , state.ifNotDoubleSpent(Transaction));
-                            if Transaction.verifySignedTransaction() && stateWitness.ifNotDoubleSpent(&Transaction.transaction.Input, &self.blockchain.lock().unwrap().tip.0) {
-                                //info!("added");
-                                info!("WORKER: NEW TRANSACTIONS ADDED!");
-                                mempool.insert(Transaction);
-                                addedTransactionHashes.push(Transaction.hash());
+                            match validation::check_transaction(Transaction, &blockchain, &stateWitness, &tip) {
+                                Ok(()) => {
+                                    //info!("added");
+                                    let fee = validation::transaction_fee(&Transaction.transaction, &stateWitness).ok().flatten().unwrap_or(0);
+                                    match mempool.add(Transaction, fee) {
+                                        Ok(evicted) => {
+                                            info!("WORKER: NEW TRANSACTIONS ADDED!");
+                                            if !evicted.is_empty() {
+                                                debug!("{:?} replaced {} mempool transactions", Transaction.hash(), evicted.len());
+                                            }
+                                            addedTransactionHashes.push(Transaction.hash());
+                                        }
+                                        // a double spend of what the mempool holds is not necessarily the peer's fault
+                                        Err(e) => debug!("transaction {:?} not added to the mempool: {}", Transaction.hash(), e),
+                                    }
+                                }
+                                // the other errors depend on our state, which may lag behind the peer's
+                                Err(TxError::InvalidSignature) => {
+                                    penalty = Some((banman::BAD_SIGNATURE, format!("transaction {:?} has an invalid signature", Transaction.hash())));
+                                    break;
+                                }
+                                Err(TxError::Coinbase) => {
+                                    penalty = Some((banman::INVALID_DATA, format!("relayed coinbase {:?}", Transaction.hash())));
+                                    break;
+                                }
+                                Err(_) => {}
                             }
                         }
                     }
                     std::mem::drop(mempool);
                     std::mem::drop(stateWitness);
+                    std::mem::drop(blockchain);
+                    if let Some((score, reason)) = penalty {
+                        self.misbehaving(&peer, score, &reason);
+                    }
                     if addedTransactionHashes.capacity() > 0 {
                         self.server.broadcast(Message::NewTransactionHashes(addedTransactionHashes));
                     }
@@ -453,15 +807,28 @@ impl Context {
 
                 Message::NewStateWitness( newState, newProof) => {
                     //info!("WORKER: NEW STATE WITNESS RECEIVED");
+                    // over the secure transport, the sender is known by its node key
+                    if let Some(address) = peer.status().node_address {
+                        debug!("State witness from {}, node address {:?}", peer.addr(), address);
+                    }
+                    if newProof.is_empty() {
+                        self.misbehaving(&peer, banman::INVALID_DATA, "sent a state witness without a proof");
+                        continue;
+                    }
+                    // the same witness reaches us from several peers: process and relay it once
+                    let id = message::state_witness_id(&newState, &newProof);
+                    peer.mark_known(&[id]);
+                    if !self.seen_witnesses.lock().unwrap().insert(id) {
+                        continue;
+                    }
                     if !self.ifArchival {
                         let mut stateWitness = self.stateWitness.lock().unwrap();
                         if !stateWitness.AccumulatorProof.contains_key(&newProof[0].0){
                             //add new states and update old states
+                            //keep every state, not only ours, so that fees of other transactions can be computed
                             stateWitness.States.clear();
                             for values in newState.iter(){
-                                if values.3 == self.local_address{
-                                    stateWitness.addStates(values.0,values.1,values.2,values.3,values.4,values.5)
-                                }
+                                stateWitness.addStates(values.0,values.1,values.2,values.3.clone(),values.4,values.5)
                             }
                             for values in newProof.iter(){
                                 if !stateWitness.AccumulatorProof.contains_key(&values.0){
@@ -469,9 +836,11 @@ impl Context {
                                 }
                             }
                             for state_key in stateWitness.States.keys(){
-                                let recp = stateWitness.States.get(state_key).unwrap().1;
+                                let recp = &stateWitness.States.get(state_key).unwrap().1;
                                 let value = stateWitness.States.get(state_key).unwrap().0;
-                                println!("FULL NODE: UPDATED STATE WITNESS: RCEP: {:?}, VALUE: {:?}", recp, value);
+                                if *recp == Script::PayToPubkeyHash(self.local_address) {
+                                    println!("FULL NODE: UPDATED STATE WITNESS: RCEP: {:?}, VALUE: {:?}", recp, value);
+                                }
                             }
                             std::mem::drop(stateWitness);
                         }
@@ -487,3 +856,15 @@ impl Context {
         }
     }
 }
+
+/// Misbehavior score of sending a block or header failing with `e`, if every node would reject it
+fn block_misbehavior(e: &BlockError) -> Option<u32> {
+    if !e.is_permanent() {
+        return None;
+    }
+    match e {
+        BlockError::InvalidProofOfWork => Some(banman::INVALID_POW),
+        BlockError::InvalidTransaction(_, TxError::InvalidSignature) => Some(banman::BAD_SIGNATURE),
+        _ => Some(banman::INVALID_DATA),
+    }
+}
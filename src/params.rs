@@ -0,0 +1,89 @@
+use crate::crypto::hash::{H256, H160};
+use num_bigint::BigUint;
+
+/// Base units in one coin. Every amount is an integer number of base units
+pub const COIN: u64 = 100_000_000;
+
+/// Consensus parameters shared by every node of a network.
+#[derive(Debug, Clone)]
+pub struct ChainParams {
+    /// Identifies the network in the handshake, so that nodes of different networks do not talk to each other
+    pub network_id: u32,
+    /// Target of the genesis block and of every block before the first retarget
+    pub initial_difficulty: H256,
+    /// Easiest target a retarget is allowed to produce
+    pub pow_limit: H256,
+    /// Desired time between two blocks, in milliseconds
+    pub block_interval: u128,
+    /// Number of blocks between two difficulty adjustments
+    pub retarget_interval: u32,
+    /// Bound on how much a single adjustment may scale the target, in either direction
+    pub max_retarget_factor: u128,
+    /// Timestamp of the genesis block, in milliseconds since the unix epoch
+    pub genesis_timestamp: u128,
+    /// Coins paid out by the genesis block: (Recipient Addr, Value)
+    pub genesis_allocation: Vec<(H160, u64)>,
+    /// Largest serialized block accepted, in bytes
+    pub max_block_size: usize,
+    /// How far ahead of the local clock a block timestamp may be, in milliseconds
+    pub max_future_drift: u128,
+    /// Base units a coinbase may create before the first halving
+    pub initial_subsidy: u64,
+    /// Number of blocks between two halvings of the subsidy
+    pub halving_interval: u32,
+    /// Number of blocks a coinbase output has to be buried under before it can be spent
+    pub coinbase_maturity: u32,
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        let mut bytes32 = [255u8;32];
+        bytes32[0]=1;
+        bytes32[1]=1;
+        ChainParams {
+            network_id: 0x0ece_0598,
+            initial_difficulty: bytes32.into(),
+            pow_limit: [255u8;32].into(),
+            block_interval: 10_000,
+            retarget_interval: 10,
+            max_retarget_factor: 4,
+            genesis_timestamp: 1_580_515_200_000, // 2020-02-01T00:00:00Z
+            genesis_allocation: Vec::new(),
+            max_block_size: 1_000_000,
+            max_future_drift: 2 * 60 * 60 * 1000,
+            initial_subsidy: 50 * COIN,
+            halving_interval: 210,
+            coinbase_maturity: 10,
+        }
+    }
+}
+
+/// Base units the coinbase of the block at `height` may create on top of the fees it collects
+pub fn block_subsidy(height: u32, params: &ChainParams) -> u64 {
+    if params.halving_interval == 0 {
+        return params.initial_subsidy;
+    }
+    let halvings = height / params.halving_interval;
+    if halvings >= 64 {
+        return 0;
+    }
+    params.initial_subsidy >> halvings
+}
+
+/// Scale `difficulty` by how long the last window actually took compared to `expected_span` (both in milliseconds)
+pub fn retarget(difficulty: &H256, actual_span: u128, expected_span: u128, params: &ChainParams) -> H256 {
+    let expected_span = std::cmp::max(expected_span, 1);
+    let actual_span = std::cmp::min(
+        std::cmp::max(actual_span, expected_span / params.max_retarget_factor),
+        expected_span * params.max_retarget_factor,
+    );
+    let target = BigUint::from_bytes_be(difficulty.as_ref()) * BigUint::from(actual_span) / BigUint::from(expected_span);
+    let pow_limit = BigUint::from_bytes_be(params.pow_limit.as_ref());
+    if target > pow_limit {
+        return params.pow_limit;
+    }
+    let bytes = target.to_bytes_be();
+    let mut bytes32 = [0u8;32];
+    bytes32[32 - bytes.len()..].copy_from_slice(&bytes);
+    bytes32.into()
+}
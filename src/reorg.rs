@@ -0,0 +1,236 @@
+use crate::accumulator::Accumulator;
+use crate::blockchain::Blockchain;
+use crate::crypto::hash::H256;
+use crate::transaction::{Mempool, StateWitness};
+use crate::validation;
+use crate::statestore;
+use log::{error, info, warn};
+
+/// The blocks that were disconnected from and connected to the main chain by a tip change.
+#[derive(Debug, Default, Clone)]
+pub struct TipChange {
+    pub disconnected: Vec<H256>,
+    pub connected: Vec<H256>,
+}
+
+impl TipChange {
+    pub fn is_reorg(&self) -> bool {
+        !self.disconnected.is_empty()
+    }
+}
+
+/// Move the chain state from `old_tip` to the current tip of `blockchain`.
+///
+/// Blocks that left the main chain are disconnected newest first: on the archival node, the outputs they spent are
+/// restored in the accumulator. The new branch is then connected oldest first. When an accumulator is given, the spends of each block of the new
+/// branch are checked against the state of its parent, since they could not be checked when the block arrived. If
+/// one fails, the block and its descendants are dropped from the blockchain, the accumulator is put back as it was,
+/// and the state follows whichever tip the blockchain falls back to. Finally the accumulator proof and all state
+/// witnesses are rebuilt for the new tip. Transactions of the disconnected blocks, except the coinbases, get the
+/// witnesses of the new tip and go back into the mempool if they are still valid on it. Last, the state is saved
+/// next to the blocks.
+pub fn update_tip(
+    blockchain: &mut Blockchain,
+    old_tip: &H256,
+    mempool: &mut Mempool,
+    stateWitness: &mut StateWitness,
+    mut accumulator: Option<&mut Accumulator>,
+) -> TipChange {
+    let new_tip = blockchain.tip();
+    if *old_tip == new_tip {
+        return TipChange::default();
+    }
+    let (disconnected, connected) = blockchain.fork_path(old_tip, &new_tip);
+    if !disconnected.is_empty() {
+        info!("REORG: DISCONNECTING {} BLOCKS, CONNECTING {} BLOCKS", disconnected.len(), connected.len());
+    }
+
+    if let Some(accumulator) = accumulator.as_mut() {
+        // a block extending the tip had its spends checked on arrival, so only a reorg needs to keep the old state
+        let saved = if disconnected.is_empty() { None } else { Some(accumulator.clone()) };
+        for hash in disconnected.iter() {
+            accumulator.disconnect_block(hash);
+        }
+        for hash in connected.iter() {
+            let block = blockchain.Blocks.get(hash).unwrap().0.clone();
+            if let Some(saved) = saved.as_ref() {
+                accumulator.update_state_witness(stateWitness, block.getparent());
+                if let Err(e) = validation::check_block_spends(&block, blockchain, stateWitness, true) {
+                    warn!("REORG: BLOCK {:?} IS INVALID: {}", hash, e);
+                    **accumulator = saved.clone();
+                    accumulator.update_state_witness(stateWitness, *old_tip);
+                    blockchain.invalidate_block(hash);
+                    return update_tip(blockchain, old_tip, mempool, stateWitness, Some(&mut **accumulator));
+                }
+            }
+            accumulator.connect_block(&block);
+        }
+        accumulator.update_parameters();
+        accumulator.update_state_witness(stateWitness, new_tip);
+    }
+
+    // without an accumulator the state cannot be rolled back: it is of no use until the archival node sends the
+    // witnesses of the new tip
+    if accumulator.is_none() && !disconnected.is_empty() {
+        stateWitness.States.clear();
+    }
+    for hash in connected.iter() {
+        let block = &blockchain.Blocks.get(hash).unwrap().0;
+        mempool.updateMempool(&block.Content.content);
+    }
+    for hash in disconnected.iter().rev() {
+        let block = &blockchain.Blocks.get(hash).unwrap().0;
+        // the coinbase only exists in its own block
+        for content in block.Content.content.iter().filter(|content| !content.transaction.isCoinbase()) {
+            // the witnesses it was confirmed with are for the old branch
+            let content = match content.rewitness(stateWitness) {
+                Some(content) => content,
+                None => continue,
+            };
+            if validation::check_transaction(&content, blockchain, stateWitness, &new_tip).is_err() {
+                continue;
+            }
+            let fee = validation::transaction_fee(&content.transaction, stateWitness).ok().flatten().unwrap_or(0);
+            let _ = mempool.add(&content, fee);
+        }
+    }
+    if let Err(e) = statestore::save(blockchain.data_dir(), &new_tip, stateWitness, accumulator.as_deref()) {
+        error!("Error saving the state at {:?}: {}", new_tip, e);
+    }
+
+    TipChange {
+        disconnected,
+        connected,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{Block, Content, Header};
+    use crate::blockchain::tests::temp_data_dir;
+    use crate::crypto::hash::tests::generate_random_hash;
+    use crate::crypto::hash::{Hashable, H160};
+    use crate::crypto::key_pair;
+    use crate::crypto::merkle::MerkleTree;
+    use crate::params::{ChainParams, COIN};
+    use crate::script::Script;
+    use crate::transaction::{coinbase, generate_transaction, sign, witness, SignedTransaction};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    fn spend(prev: H256, index: u32, value: u64, recipient: H160, key: &Ed25519KeyPair) -> SignedTransaction {
+        let transaction = generate_transaction(
+            &vec![prev],
+            &vec![index],
+            &vec![witness::default()],
+            &vec![value],
+            &vec![recipient],
+        );
+        let signature = sign(&transaction, key);
+        SignedTransaction::new(&transaction, &signature, &key.public_key())
+    }
+
+    /// A child of `parent` at `height` with a coinbase in front of `content`
+    fn block_with(parent: &H256, height: u32, mut content: Vec<SignedTransaction>) -> Block {
+        content.insert(0, coinbase(height, &H160::default(), COIN));
+        let difficulty = [255u8; 32].into();
+        Block {
+            Header: Header {
+                parent: *parent,
+                nonce: 0,
+                difficulty,
+                timestamp: 0,
+                merkleRoot: MerkleTree::new(&content).root(),
+            },
+            Content: Content { content },
+        }
+    }
+
+    #[test]
+    fn reorg_restores_mempool_and_accumulator() {
+        let dir = temp_data_dir("reorg");
+        let mut blockchain = Blockchain::new(&dir, ChainParams::default()).unwrap();
+        let mut mempool = Mempool::new();
+        let mut stateWitness = StateWitness::new();
+        let mut accumulator = Accumulator::new();
+        let genesis_hash = blockchain.tip();
+
+        let alice_key = key_pair::random();
+        let alice = key_pair::address(alice_key.public_key().as_ref());
+        let bob: H160 = generate_random_hash().into();
+        let ico = generate_random_hash();
+        accumulator.hash_to_prime(ico, 0, 100 * COIN, Script::PayToPubkeyHash(alice));
+        let ico_prime = accumulator.accumulator.get(&(ico, 0)).unwrap().2;
+
+        // main chain: genesis <- a1, where a1 spends the ICO output to bob
+        let pay_bob = spend(ico, 0, 100 * COIN, bob, &alice_key);
+        let a1 = block_with(&genesis_hash, 1, vec![pay_bob.clone()]);
+        let old_tip = blockchain.tip();
+        blockchain.insert(&a1);
+        let change = update_tip(&mut blockchain, &old_tip, &mut mempool, &mut stateWitness, Some(&mut accumulator));
+        assert!(!change.is_reorg());
+        assert!(!accumulator.accumulator.contains_key(&(ico, 0)));
+        assert!(accumulator.accumulator.contains_key(&(pay_bob.hash(), 0)));
+
+        // side chain: genesis <- b1 <- b2 without the payment overtakes the tip
+        let b1 = block_with(&genesis_hash, 1, vec![]);
+        let b2 = block_with(&b1.hash(), 2, vec![]);
+        let old_tip = blockchain.tip();
+        blockchain.insert(&b1);
+        blockchain.insert(&b2);
+        let change = update_tip(&mut blockchain, &old_tip, &mut mempool, &mut stateWitness, Some(&mut accumulator));
+        assert!(change.is_reorg());
+        assert_eq!(change.disconnected, vec![a1.hash()]);
+        assert_eq!(change.connected, vec![b1.hash(), b2.hash()]);
+
+        // the ICO output is unspent again with its original prime, and the payment is back in the mempool with the
+        // witness of the new tip
+        assert_eq!(accumulator.accumulator.get(&(ico, 0)).unwrap().2, ico_prime);
+        let pay_bob = pay_bob.rewitness(&stateWitness).unwrap();
+        assert!(mempool.Transactions.contains_key(&pay_bob.hash()));
+        assert_eq!(validation::check_transaction(&pay_bob, &blockchain, &stateWitness, &b2.hash()), Ok(()));
+        assert!(!accumulator.accumulator.contains_key(&(pay_bob.hash(), 0)));
+        assert!(stateWitness.AccumulatorProof.contains_key(&b2.hash()));
+        let state = stateWitness.States.get(&(ico, 0)).unwrap();
+        assert_eq!(state.1, Script::PayToPubkeyHash(alice));
+        assert_eq!(state.3 * (state.2 as u128), *stateWitness.AccumulatorProof.get(&b2.hash()).unwrap());
+
+        // a longer branch whose second block spends an output that does not exist is dropped when connected
+        let mut c1 = block_with(&genesis_hash, 1, vec![]);
+        c1.Header.timestamp = 1;
+        let c2 = block_with(&c1.hash(), 2, vec![spend(generate_random_hash(), 0, COIN, alice, &alice_key)]);
+        let c3 = block_with(&c2.hash(), 3, vec![]);
+        let old_tip = blockchain.tip();
+        blockchain.insert(&c1);
+        blockchain.insert(&c2);
+        blockchain.insert(&c3);
+        assert_eq!(blockchain.tip(), c3.hash());
+        let change = update_tip(&mut blockchain, &old_tip, &mut mempool, &mut stateWitness, Some(&mut accumulator));
+        assert!(!change.is_reorg());
+        assert_eq!(blockchain.tip(), b2.hash());
+        assert!(!blockchain.Blocks.contains_key(&c2.hash()) && !blockchain.Blocks.contains_key(&c3.hash()));
+        // and stays dropped when it is announced again
+        let now = c3.Header.timestamp + 1000;
+        assert_eq!(validation::check_header(&c2.Header, &blockchain, now), Err(validation::BlockError::InvalidAncestor(c2.hash())));
+        assert_eq!(validation::check_header(&c3.Header, &blockchain, now), Err(validation::BlockError::InvalidAncestor(c2.hash())));
+        assert_eq!(blockchain.insert(&c2), b2.hash());
+        assert_eq!(blockchain.insert(&c3), b2.hash());
+        assert_eq!(accumulator.accumulator.get(&(ico, 0)).unwrap().2, ico_prime);
+        let state = stateWitness.States.get(&(ico, 0)).unwrap();
+        assert_eq!(state.3 * (state.2 as u128), *stateWitness.AccumulatorProof.get(&b2.hash()).unwrap());
+
+        // a stateless node cannot roll its state back, and drops it
+        let mut d1 = block_with(&genesis_hash, 1, vec![]);
+        d1.Header.timestamp = 2;
+        let d2 = block_with(&d1.hash(), 2, vec![]);
+        let d3 = block_with(&d2.hash(), 3, vec![]);
+        let old_tip = blockchain.tip();
+        blockchain.insert(&d1);
+        blockchain.insert(&d2);
+        blockchain.insert(&d3);
+        let change = update_tip(&mut blockchain, &old_tip, &mut mempool, &mut stateWitness, None);
+        assert!(change.is_reorg());
+        assert!(stateWitness.States.is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
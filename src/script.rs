@@ -0,0 +1,167 @@
+use crate::crypto::hash::H160;
+use crate::crypto::key_pair;
+use ring::signature;
+use serde::{Deserialize, Serialize};
+
+/// Most keys a multisig script may list
+pub const MAX_MULTISIG_KEYS: usize = 16;
+
+/// Conditions an output sets on spending it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Script {
+    /// Spendable with a signature by the key whose address this is. The default, single-key script
+    PayToPubkeyHash(H160),
+    /// Spendable with signatures by `required` of the keys, given in the order of the keys
+    Multisig { required: u8, public_keys: Vec<Vec<u8>> },
+    /// Spendable by revealing a script whose address this is, along with what unlocks that script
+    PayToScriptHash(H160),
+}
+
+/// What an input provides to satisfy the script of the output it spends
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Unlock {
+    /// Unlocks `PayToPubkeyHash`
+    Signature { public_key: Vec<u8>, signature: Vec<u8> },
+    /// Unlocks `Multisig`: one signature per required key
+    Multisig(Vec<Vec<u8>>),
+    /// Unlocks `PayToScriptHash`: the redeem script, and what unlocks it
+    ScriptHash(Script, RedeemUnlock),
+}
+
+/// What unlocks a redeem script. Redeem scripts do not nest, so neither does this, which keeps decoding an
+/// unlock from a peer flat
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum RedeemUnlock {
+    Signature { public_key: Vec<u8>, signature: Vec<u8> },
+    Multisig(Vec<Vec<u8>>),
+}
+
+impl From<RedeemUnlock> for Unlock {
+    fn from(unlock: RedeemUnlock) -> Self {
+        match unlock {
+            RedeemUnlock::Signature { public_key, signature } => Unlock::Signature { public_key, signature },
+            RedeemUnlock::Multisig(signatures) => Unlock::Multisig(signatures),
+        }
+    }
+}
+
+impl Script {
+    /// The address of the script, which a `PayToScriptHash` output pays to. It is derived like the address
+    /// of a public key, from the serialized script
+    pub fn address(&self) -> H160 {
+        key_pair::address(&bincode::serialize(self).unwrap())
+    }
+
+    /// A `PayToScriptHash` output for this script
+    pub fn pay_to_script(&self) -> Script {
+        Script::PayToScriptHash(self.address())
+    }
+}
+
+impl Unlock {
+    /// Check what can be checked without the output being spent: signatures next to their key, and redeem
+    /// scripts, which are revealed in full. Multisig signatures need the keys of the output
+    pub fn verify_context_free(&self, digest: &[u8]) -> bool {
+        match self {
+            Unlock::Signature { public_key, signature } => verify(public_key, digest, signature),
+            Unlock::Multisig(_) => true,
+            Unlock::ScriptHash(redeem, unlock) => match redeem {
+                Script::PayToScriptHash(_) => false,
+                _ => evaluate(redeem, &unlock.clone().into(), digest),
+            },
+        }
+    }
+}
+
+/// Whether `unlock` satisfies `script` for a transaction with signature digest `digest`
+pub fn evaluate(script: &Script, unlock: &Unlock, digest: &[u8]) -> bool {
+    match (script, unlock) {
+        (Script::PayToPubkeyHash(address), Unlock::Signature { public_key, signature }) => {
+            key_pair::address(public_key) == *address && verify(public_key, digest, signature)
+        }
+        (Script::Multisig { required, public_keys }, Unlock::Multisig(signatures)) => {
+            // a script no key can satisfy, or one anyone can, is never satisfied
+            if *required == 0 || *required as usize > public_keys.len() || public_keys.len() > MAX_MULTISIG_KEYS {
+                return false;
+            }
+            if signatures.len() != *required as usize {
+                return false;
+            }
+            // each signature is checked against the keys left after the one the previous signature matched
+            let mut keys = public_keys.iter();
+            signatures.iter().all(|signature| keys.any(|key| verify(key, digest, signature)))
+        }
+        (Script::PayToScriptHash(address), Unlock::ScriptHash(redeem, unlock)) => match redeem {
+            // redeem scripts do not nest
+            Script::PayToScriptHash(_) => false,
+            _ => redeem.address() == *address && evaluate(redeem, &unlock.clone().into(), digest),
+        },
+        _ => false,
+    }
+}
+
+fn verify(public_key: &[u8], digest: &[u8], signature: &[u8]) -> bool {
+    signature::UnparsedPublicKey::new(&signature::ED25519, public_key).verify(digest, signature).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    fn public_key(key: &Ed25519KeyPair) -> Vec<u8> {
+        key.public_key().as_ref().to_vec()
+    }
+
+    fn sign(key: &Ed25519KeyPair, digest: &[u8]) -> Vec<u8> {
+        key.sign(digest).as_ref().to_vec()
+    }
+
+    #[test]
+    fn evaluate_scripts() {
+        let digest = b"transaction digest";
+        let keys: Vec<Ed25519KeyPair> = (0..3).map(|_| key_pair::random()).collect();
+
+        let p2pkh = Script::PayToPubkeyHash(key_pair::address(&public_key(&keys[0])));
+        let unlock = Unlock::Signature { public_key: public_key(&keys[0]), signature: sign(&keys[0], digest) };
+        assert!(evaluate(&p2pkh, &unlock, digest));
+        assert!(!evaluate(&p2pkh, &unlock, b"other digest"));
+        let other = Unlock::Signature { public_key: public_key(&keys[1]), signature: sign(&keys[1], digest) };
+        assert!(other.verify_context_free(digest));
+        assert!(!evaluate(&p2pkh, &other, digest));
+
+        // 2 of 3, with signatures in key order
+        let multisig = Script::Multisig { required: 2, public_keys: keys.iter().map(public_key).collect() };
+        let unlock = Unlock::Multisig(vec![sign(&keys[0], digest), sign(&keys[2], digest)]);
+        assert!(evaluate(&multisig, &unlock, digest));
+        assert!(!evaluate(&multisig, &Unlock::Multisig(vec![sign(&keys[2], digest), sign(&keys[0], digest)]), digest));
+        assert!(!evaluate(&multisig, &Unlock::Multisig(vec![sign(&keys[1], digest), sign(&keys[1], digest)]), digest));
+        assert!(!evaluate(&multisig, &Unlock::Multisig(vec![sign(&keys[1], digest)]), digest));
+        let anyone = Script::Multisig { required: 0, public_keys: vec![] };
+        assert!(!evaluate(&anyone, &Unlock::Multisig(vec![]), digest));
+
+        // the same multisig behind its address
+        let p2sh = multisig.pay_to_script();
+        let wrapped = Unlock::ScriptHash(multisig.clone(), RedeemUnlock::Multisig(vec![sign(&keys[0], digest), sign(&keys[2], digest)]));
+        assert!(wrapped.verify_context_free(digest));
+        assert!(evaluate(&p2sh, &wrapped, digest));
+        let single = Script::Multisig { required: 1, public_keys: vec![public_key(&keys[0])] };
+        let substituted = Unlock::ScriptHash(single, RedeemUnlock::Multisig(vec![sign(&keys[0], digest)]));
+        assert!(substituted.verify_context_free(digest));
+        assert!(!evaluate(&p2sh, &substituted, digest));
+        assert!(!evaluate(&multisig, &wrapped, digest));
+    }
+
+    #[test]
+    fn nested_unlock_does_not_decode() {
+        // an unlock wrapped in itself many times over, as a peer could send it
+        let script = Script::PayToPubkeyHash(key_pair::address(b"key"));
+        let flat = bincode::serialize(&Unlock::ScriptHash(script, RedeemUnlock::Multisig(vec![]))).unwrap();
+        // the inner unlock is its variant index and an empty list
+        let (wrapper, inner) = flat.split_at(flat.len() - 12);
+        let mut nested = wrapper.repeat(100_000);
+        nested.extend_from_slice(inner);
+        assert!(bincode::deserialize::<Unlock>(&nested).is_err());
+        assert!(bincode::deserialize::<Unlock>(&flat).is_ok());
+    }
+}
@@ -0,0 +1,89 @@
+use crate::accumulator::Accumulator;
+use crate::crypto::hash::H256;
+use crate::transaction::StateWitness;
+use serde::{Deserialize, Serialize};
+
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+const STATE_FILE: &str = "state.dat";
+
+/// The chain state as of the block `tip`: the state witness and, on the archival node, the accumulator with the
+/// undo data of the blocks it connected.
+///
+/// It is written next to the block store after every tip change. Blocks are stored before the state moves to
+/// them, so a state that lags behind the stored chain after a crash catches up by moving from `tip` to the
+/// current tip of the blockchain.
+#[derive(Serialize, Deserialize)]
+pub struct ChainState {
+    pub tip: H256,
+    pub stateWitness: StateWitness,
+    pub accumulator: Option<Accumulator>,
+}
+
+/// Replace the saved state of `dir`
+pub fn save(dir: &Path, tip: &H256, stateWitness: &StateWitness, accumulator: Option<&Accumulator>) -> std::io::Result<()> {
+    #[derive(Serialize)]
+    struct Borrowed<'a> {
+        tip: &'a H256,
+        stateWitness: &'a StateWitness,
+        accumulator: Option<&'a Accumulator>,
+    }
+    let serialized = bincode::serialize(&Borrowed { tip, stateWitness, accumulator }).unwrap();
+    let path = dir.join(STATE_FILE);
+    let tmp = path.with_extension("tmp");
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(&serialized)?;
+    file.sync_all()?;
+    fs::rename(&tmp, &path)
+}
+
+/// The state saved in `dir`, if there is one
+pub fn load(dir: &Path) -> std::io::Result<Option<ChainState>> {
+    let serialized = match fs::read(dir.join(STATE_FILE)) {
+        Ok(serialized) => serialized,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    bincode::deserialize(&serialized)
+        .map(Some)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::tests::temp_data_dir;
+    use crate::crypto::hash::tests::generate_random_hash;
+    use crate::params::COIN;
+    use crate::script::Script;
+
+    #[test]
+    fn save_and_load() {
+        let dir = temp_data_dir("statestore");
+        fs::create_dir_all(&dir).unwrap();
+        assert!(load(&dir).unwrap().is_none());
+
+        let tip = generate_random_hash();
+        let output = generate_random_hash();
+        let mut accumulator = Accumulator::new();
+        accumulator.hash_to_prime(output, 0, COIN, Script::PayToPubkeyHash(generate_random_hash().into()));
+        let mut stateWitness = StateWitness::new();
+        accumulator.update_state_witness(&mut stateWitness, tip);
+        save(&dir, &tip, &stateWitness, Some(&accumulator)).unwrap();
+
+        let saved = load(&dir).unwrap().unwrap();
+        assert_eq!(saved.tip, tip);
+        assert_eq!(saved.stateWitness.States, stateWitness.States);
+        assert_eq!(saved.stateWitness.AccumulatorProof, stateWitness.AccumulatorProof);
+        let restored = saved.accumulator.unwrap();
+        assert_eq!(restored.accumulator, accumulator.accumulator);
+        assert_eq!(restored.prime_set, accumulator.prime_set);
+
+        // a stateless node saves no accumulator
+        save(&dir, &tip, &stateWitness, None).unwrap();
+        assert!(load(&dir).unwrap().unwrap().accumulator.is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,102 @@
+use log::warn;
+
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// keep at most this many peer samples, one per host, so connecting many peers cannot shift the median at will
+const MAX_SAMPLES: usize = 200;
+
+/// Local wall-clock time in milliseconds since the unix epoch
+pub fn local_time() -> u128 {
+    SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_millis()
+}
+
+/// Network-adjusted time: the local clock corrected by the median of the clock offsets peers reported
+/// during the handshake. Offsets are in milliseconds, kept per host while it stays connected.
+pub struct TimeData {
+    offsets: HashMap<IpAddr, i128>,
+    max_adjustment: i128,
+}
+
+impl TimeData {
+    /// A median offset larger than `max_adjustment` is ignored and the local clock is used as is
+    pub fn new(max_adjustment: u128) -> Self {
+        TimeData {
+            offsets: HashMap::new(),
+            max_adjustment: max_adjustment as i128,
+        }
+    }
+
+    /// Record the time a peer reported, compared to the local clock now. Only the first sample of a host counts,
+    /// however many connections it opens
+    pub fn add_sample(&mut self, source: IpAddr, peer_time: u128) {
+        if self.offsets.len() >= MAX_SAMPLES || self.offsets.contains_key(&source) {
+            return;
+        }
+        self.offsets.insert(source, peer_time as i128 - local_time() as i128);
+    }
+
+    /// Forget the samples of hosts that are no longer connected
+    pub fn retain_sources(&mut self, connected: &HashSet<IpAddr>) {
+        self.offsets.retain(|source, _| connected.contains(source));
+    }
+
+    /// Median of the peer offsets, counting the local clock as one sample with no offset
+    pub fn offset(&self) -> i128 {
+        if self.offsets.is_empty() {
+            return 0;
+        }
+        let mut samples: Vec<i128> = self.offsets.values().cloned().collect();
+        samples.push(0);
+        samples.sort();
+        let median = samples[samples.len() / 2];
+        if median.abs() > self.max_adjustment {
+            warn!("Peers' median clock offset of {} ms is too large, please check your clock", median);
+            return 0;
+        }
+        median
+    }
+
+    pub fn adjusted_time(&self) -> u128 {
+        (local_time() as i128 + self.offset()) as u128
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn median_offset() {
+        let mut timedata = TimeData::new(60_000);
+        assert_eq!(timedata.offset(), 0);
+        let now = local_time();
+        timedata.add_sample("127.0.0.1".parse().unwrap(), now + 10_000);
+        timedata.add_sample("127.0.0.2".parse().unwrap(), now + 20_000);
+        timedata.add_sample("127.0.0.3".parse().unwrap(), now + 30_000);
+        // samples are 0 (local), ~10s, ~20s and ~30s
+        let offset = timedata.offset();
+        assert!(offset > 19_000 && offset <= 20_000);
+        // a single outlier cannot move the median outside the honest samples
+        timedata.add_sample("127.0.0.4".parse().unwrap(), now + 3_000_000);
+        let offset = timedata.offset();
+        assert!(offset > 19_000 && offset <= 20_000);
+        // nor can it by connecting again and again
+        for _ in 0..5 {
+            timedata.add_sample("127.0.0.4".parse().unwrap(), now + 3_000_000);
+        }
+        let offset = timedata.offset();
+        assert!(offset > 19_000 && offset <= 20_000);
+        // a median beyond the allowed adjustment is ignored
+        for host in 5..10 {
+            timedata.add_sample(format!("127.0.0.{}", host).parse().unwrap(), now + 3_000_000);
+        }
+        assert_eq!(timedata.offset(), 0);
+        // samples go away with their hosts
+        let connected: HashSet<IpAddr> = ["127.0.0.1", "127.0.0.2", "127.0.0.3"].iter().map(|ip| ip.parse().unwrap()).collect();
+        timedata.retain_sources(&connected);
+        let offset = timedata.offset();
+        assert!(offset > 9_000 && offset <= 20_000);
+    }
+}
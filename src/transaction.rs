@@ -10,7 +10,7 @@ use ring::{digest};
 
 use rand::{thread_rng, Rng};
 use rand::distributions::Alphanumeric;
-use log::info;
+use log::{error, info};
 
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
 use std::time;
@@ -26,6 +26,10 @@ use std::ops::Deref;
 use num_integer::Roots;
 //use std::intrinsics::fabsf32;
 use crate::blockchain::Blockchain;
+use crate::statestore;
+use crate::validation;
+use crate::params::COIN;
+use crate::script::{Script, Unlock};
 //use std::intrinsics::prefetch_read_data;
 
 //Update: add witness to txs
@@ -40,20 +44,46 @@ pub struct input {
     pub prevTransaction: H256,
     pub preOutputIndex: u32,
     pub witness: witness,
+    pub relativeLock: u32, // the spending block must be at least this many blocks above the spent output's, 0 for none
 }
 
-#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct output {
-    pub recpAddress: H160,
-    pub value: f32,
+    pub script: Script, // conditions on spending the output
+    pub value: u64, // in base units
     pub index: u32,
 }
 
+/// Earliest block a transaction may be included in
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockTime {
+    None,
+    /// A block at this height or above
+    Height(u32),
+    /// A block whose parent has a median time past at or after this, in milliseconds since the unix epoch
+    Time(u128),
+}
+
+impl Default for LockTime {
+    fn default() -> Self {
+        LockTime::None
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct Transaction {
     pub Input: Vec<input>,
     pub Output: Vec<output>,
+    pub lockTime: LockTime,
+    pub replaceable: bool, // opts in to being replaced in the mempool by a conflicting transaction paying more
+}
 
+impl Transaction {
+    /// A coinbase has a single input spending the null outpoint; the input's index carries the block height
+    /// so that coinbases of different blocks never share a hash
+    pub fn isCoinbase(&self) -> bool {
+        self.Input.len() == 1 && self.Input[0].prevTransaction == H256::default()
+    }
 }
 
 impl Hashable for Transaction {
@@ -66,8 +96,7 @@ impl Hashable for Transaction {
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct SignedTransaction {
     pub transaction: Transaction,
-    pub signature: Vec<u8>,
-    pub publicKey: Vec<u8>,
+    pub unlocks: Vec<Unlock>, // what satisfies the script of each spent output, in input order
 }
 
 impl Hashable for SignedTransaction {
@@ -77,36 +106,52 @@ impl Hashable for SignedTransaction {
 }
 
 impl SignedTransaction {
+    /// A transaction whose inputs all spend pay-to-pubkey-hash outputs of a single key
     pub fn new(t: &Transaction, signature: &Signature, public_key: &<Ed25519KeyPair as KeyPair>::PublicKey) -> Self{
         let mut transaction = t.clone();
-        let mut signature = signature.as_ref().to_vec();
-        let mut publicKey = public_key.as_ref().to_vec();
-        return SignedTransaction{transaction: transaction, signature:signature, publicKey:publicKey}
+        let unlock = Unlock::Signature{public_key: public_key.as_ref().to_vec(), signature: signature.as_ref().to_vec()};
+        let unlocks = vec![unlock; t.Input.len()];
+        return SignedTransaction{transaction: transaction, unlocks: unlocks}
     }
     // CODE SIGNATURE CHECK
+    // Every input needs unlocking data; signatures are checked as far as possible without the spent outputs
     pub fn verifySignedTransaction(&self) -> bool {
-        //info!("checking signature...");
-        let public_key = self.publicKey.clone();
-        let signature= self.signature.clone();
-        //println!("pubkey: {:?}, sig: {:?} " ,public_key, signature);
-        let public_key_ = ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, public_key[..].as_ref());
-        return public_key_.verify(&bincode::serialize(&self.transaction).unwrap()[..],signature[..].as_ref()) == Ok(());
+        let digest = signature_digest(&self.transaction);
+        return self.unlocks.len() == self.transaction.Input.len()
+            && self.unlocks.iter().all(|unlock| unlock.verify_context_free(digest.as_ref()));
     }
+
+    /// The same transaction with the witness of each input taken from `stateWitness`, so that it spends against
+    /// the tip the state is for. `None` if an input is not in the state
+    pub fn rewitness(&self, stateWitness: &StateWitness) -> Option<SignedTransaction> {
+        let mut rewitnessed = self.clone();
+        for input in rewitnessed.transaction.Input.iter_mut() {
+            let state = stateWitness.States.get(&(input.prevTransaction, input.preOutputIndex))?;
+            input.witness = witness{prime_number: state.2, witness: state.3};
+        }
+        Some(rewitnessed)
+    }
+}
+
+/// What signatures of a transaction sign: the hash of the serialized transaction, without the unlocking data and
+/// without the input witnesses, which only hold for one tip and are renewed when the transaction is carried over
+pub fn signature_digest(t: &Transaction) -> H256 {
+    let mut unwitnessed = t.clone();
+    for input in unwitnessed.Input.iter_mut() {
+        input.witness = witness::default();
+    }
+    unwitnessed.hash()
 }
 
 /// Create digital signature of a transaction
 pub fn sign(t: &Transaction, key: &Ed25519KeyPair) -> Signature {
-    //unimplemented!()
-    let t_serialized = bincode::serialize(t).unwrap();
-    let t_signature = key.sign(&t_serialized);
-    t_signature
+    key.sign(signature_digest(t).as_ref())
 }
 
 /// Verify digital signature of a transaction, using public key instead of secret key
 pub fn verify(t: &Transaction, public_key: &<Ed25519KeyPair as KeyPair>::PublicKey, signature: &Signature) -> bool {
-    let t_serialized = bincode::serialize(&t).unwrap();
     let public_key_ = ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, public_key.as_ref());
-    if public_key_.verify(&t_serialized,signature.as_ref()) == Ok(())  {   return true;    }
+    if public_key_.verify(signature_digest(t).as_ref(),signature.as_ref()) == Ok(())  {   return true;    }
     else {   return false;   }
 
 }
@@ -126,32 +171,153 @@ impl StateSet {
 */
 
 //transaction Handle Begins
+/// Why a transaction was kept out of the mempool
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MempoolError {
+    Duplicate,
+    /// Spends an output that this mempool transaction already spends, which did not opt in to replacement
+    Conflict(H256),
+    /// A replacement has to pay more than everything it evicts, and a higher fee rate than what it conflicts with
+    InsufficientFee,
+}
+
+impl std::fmt::Display for MempoolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MempoolError::Duplicate => write!(f, "already in the mempool"),
+            MempoolError::Conflict(hash) => write!(f, "conflicts with {:?}, which is not replaceable", hash),
+            MempoolError::InsufficientFee => write!(f, "fee too low to replace the conflicting transactions"),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct Mempool {
     pub Transactions: HashMap<H256,SignedTransaction>, // hash <-> transaction(signed)
+    pub Spent: HashMap<(H256, u32),H256>, // (prev TX Hash, prev Output Index) <-> hash of the transaction spending it
+    pub Fees: HashMap<H256,u64>, // hash <-> fee paid, for transactions admitted with `add`
 }
 
 impl Mempool {
     pub fn new() -> Self{
-        let hashmap:HashMap<H256,SignedTransaction> = HashMap::new();
-        return Mempool{Transactions:hashmap,}
+        return Mempool::default()
+    }
+
+    /// Admit a valid transaction paying `fee`. If it spends outputs that mempool transactions already spend, it
+    /// replaces them and their descendants, provided each of them opted in to replacement, and it pays a higher
+    /// fee than all of them together and a higher fee rate than each. Returns the evicted transactions.
+    ///
+    /// This is the only way into the mempool. The node checks transactions with `validation::check_transaction`
+    /// first, which wants every input in the state, so a mempool transaction never spends another one and the
+    /// descendants of a replaced transaction are always none; they only exist when transactions are added unchecked
+    pub fn add(&mut self, tx: &SignedTransaction, fee: u64) -> Result<Vec<H256>, MempoolError> {
+        if self.Transactions.contains_key(&tx.hash()) {
+            return Err(MempoolError::Duplicate);
+        }
+        let conflicts = self.conflicts(tx);
+        let size = bincode::serialized_size(tx).unwrap();
+        for hash in conflicts.iter() {
+            let conflict = self.Transactions.get(hash).unwrap();
+            if !conflict.transaction.replaceable {
+                return Err(MempoolError::Conflict(*hash));
+            }
+            // fee / size > conflict fee / conflict size, without dividing
+            let conflict_fee = self.fee(hash);
+            let conflict_size = bincode::serialized_size(conflict).unwrap();
+            if fee as u128 * conflict_size as u128 <= conflict_fee as u128 * size as u128 {
+                return Err(MempoolError::InsufficientFee);
+            }
+        }
+        let mut evicted = Vec::<H256>::new();
+        for hash in conflicts.iter() {
+            self.with_descendants(hash, &mut evicted);
+        }
+        let evicted_fees: u128 = evicted.iter().map(|hash| self.fee(hash) as u128).sum();
+        if !evicted.is_empty() && fee as u128 <= evicted_fees {
+            return Err(MempoolError::InsufficientFee);
+        }
+        for hash in evicted.iter() {
+            self.remove(hash);
+        }
+        for input in tx.transaction.Input.iter() {
+            self.Spent.insert((input.prevTransaction, input.preOutputIndex), tx.hash());
+        }
+        self.Transactions.insert(tx.hash(), tx.clone());
+        self.Fees.insert(tx.hash(), fee);
+        Ok(evicted)
+    }
+
+    /// Hashes of the mempool transactions spending an output `tx` spends
+    pub fn conflicts(&self, tx: &SignedTransaction) -> Vec<H256> {
+        let mut conflicts = Vec::<H256>::new();
+        for input in tx.transaction.Input.iter() {
+            if let Some(hash) = self.Spent.get(&(input.prevTransaction, input.preOutputIndex)) {
+                if *hash != tx.hash() && !conflicts.contains(hash) {
+                    conflicts.push(*hash);
+                }
+            }
+        }
+        conflicts
     }
 
-    pub fn insert(&mut self, tx: &SignedTransaction) {
-        let last_tx = tx.clone();
-        self.Transactions.insert(tx.hash(), last_tx);
+    fn fee(&self, hash: &H256) -> u64 {
+        self.Fees.get(hash).cloned().unwrap_or(0)
     }
 
+    /// Collect `hash` and the mempool transactions spending its outputs, recursively
+    fn with_descendants(&self, hash: &H256, collected: &mut Vec<H256>) {
+        if collected.contains(hash) {
+            return;
+        }
+        let tx = match self.Transactions.get(hash) {
+            Some(tx) => tx,
+            None => return,
+        };
+        collected.push(*hash);
+        for output in tx.transaction.Output.iter() {
+            if let Some(child) = self.Spent.get(&(*hash, output.index)) {
+                self.with_descendants(child, collected);
+            }
+        }
+    }
+
+    /// Remove a transaction and its descendants
+    pub fn evict(&mut self, hash: &H256) {
+        let mut evicted = Vec::<H256>::new();
+        self.with_descendants(hash, &mut evicted);
+        for hash in evicted.iter() {
+            self.remove(hash);
+        }
+    }
+
+    fn remove(&mut self, hash: &H256) {
+        if let Some(tx) = self.Transactions.remove(hash) {
+            for input in tx.transaction.Input.iter() {
+                let outpoint = (input.prevTransaction, input.preOutputIndex);
+                if self.Spent.get(&outpoint) == Some(hash) {
+                    self.Spent.remove(&outpoint);
+                }
+            }
+        }
+        self.Fees.remove(hash);
+    }
+
+    /// Drop the transactions of a connected block, along with the transactions that spend the same outputs
+    /// and their descendants, which can no longer be confirmed
     pub fn updateMempool(&mut self, SignedTransaction: &Vec<SignedTransaction>){
         for signedTransaction in SignedTransaction{
-            if self.Transactions.contains_key(&signedTransaction.hash()){
-                self.Transactions.remove(&signedTransaction.hash());
+            self.remove(&signedTransaction.hash());
+            for hash in self.conflicts(signedTransaction) {
+                self.evict(&hash);
             }
         }
 
     }
 }
 
+// fee the transaction generator pays per transaction, when the change covers it
+const TX_FEE: u64 = COIN / 10;
+
 enum ControlSignal {
     Start(u64), // the number controls the lambda of interval between block generation
     Exit,
@@ -263,7 +429,6 @@ impl Context {
         let mut archival_address= Vec::<H160>::new();
         let mut other_address = Vec::<H160>::new();
         let mut all_address = Vec::<H160>::new();
-        let mut tx_set = HashSet::<(H256,u32)>::new();
         let mut block_set = HashSet::<H256>::new();
 
         // main transacation generating loop
@@ -327,19 +492,30 @@ impl Context {
             }
             //TODO ICO for Archival node
             if self.ifArchival && !ICO {
+                let blockchain = self.blockchain.lock().unwrap();
                 let mut stateWitness = self.stateWitness.lock().unwrap();
                 let mut accumulator = self.accumulator.lock().unwrap();
                 //let mut statePrimeWitness = Vec::<(H256,u32,u32)>::new();
-                //Add states to accumulator
-                for i in 0..all_address.len() {
-                    let rand_u8:u8 = rand::thread_rng().gen();
-                    let hash = <H256>::from(digest::digest(&digest::SHA256, &[rand_u8]));
-                    accumulator.hash_to_prime(hash, 0, 100.0, all_address[i]);
+                //Add states to accumulator, unless they were restored from an earlier run
+                if accumulator.accumulator.is_empty() {
+                    for i in 0..all_address.len() {
+                        let rand_u8:u8 = rand::thread_rng().gen();
+                        let hash = <H256>::from(digest::digest(&digest::SHA256, &[rand_u8]));
+                        accumulator.hash_to_prime(hash, 0, 100 * COIN, Script::PayToPubkeyHash(all_address[i]));
+                    }
+                    //Add the genesis allocation, if the chain has one
+                    let genesis = blockchain.Blocks.get(&blockchain.genesis_hash).unwrap().0.clone();
+                    for content in genesis.Content.content.iter() {
+                        for output in content.transaction.Output.iter() {
+                            accumulator.hash_to_prime(content.hash(), output.index, output.value, output.script.clone());
+                        }
+                    }
                 }
+                let tip = blockchain.tip();
 
                 //Calculate accumulator proof and Add it to Accumulator Proof
                 let A = accumulator.accumulate();
-                stateWitness.AccumulatorProof.insert(self.blockchain.lock().unwrap().tip.0,A);
+                stateWitness.AccumulatorProof.insert(tip,A);
 
                 for (key, values) in accumulator.accumulator.iter() {
 
@@ -347,22 +523,26 @@ impl Context {
                     let mut witness = A / (values.2 as u128);
                     println!("a:{:?}, g: {:?}, A:{:?}, witness{:?}",values.2, accumulator.g, A, witness);
 
-                    stateWitness.addStates(key.0, key.1, values.0,values.1, values.2, witness );
+                    stateWitness.addStates(key.0, key.1, values.0,values.1.clone(), values.2, witness );
                 }
                 for state_key in stateWitness.States.keys(){
-                    let recp = stateWitness.States.get(state_key).unwrap().1;
+                    let recp = &stateWitness.States.get(state_key).unwrap().1;
                     let value = stateWitness.States.get(state_key).unwrap().0;
                     println!("FULL NODE: UPDATED STATE WITNESS: RCEP: {:?}, VALUE: {:?}", recp, value);
                 }
 
-                self.server.broadcast(Message::NewStateWitness(stateWitness.getAllStates(),stateWitness.getNewProof(&self.blockchain.lock().unwrap().tip.0)));
+                self.server.broadcast(Message::NewStateWitness(stateWitness.getAllStates(),stateWitness.getNewProof(&tip)));
                 if stateWitness.States.capacity() > 0{
                     ICO = true;
                     println!("ARCHIVAL NODE: {:?}, ICO INITIATED", self.local_address);
                 }
+                if let Err(e) = statestore::save(blockchain.data_dir(), &tip, &stateWitness, Some(&accumulator)) {
+                    error!("Error saving the state at {:?}: {}", tip, e);
+                }
                 //Broadcast the witnesses
                 std::mem::drop(stateWitness);
                 std::mem::drop(accumulator);
+                std::mem::drop(blockchain);
 
             }
 
@@ -371,14 +551,18 @@ impl Context {
             //OLD read states to obtain ledger: balance, ready to generate txs
             //NEW TODO: Check State Witnesses and Update Balance for FULL NODE
             let mut myStateWitness = Vec::<(H256, u32, u32, u128)>::new();
-            let mut all_value = 0 as f32;
+            let mut all_value: u64 = 0;
             if !self.ifArchival{
+                // coinbases we mined recently cannot be spent yet
+                let blockchain = self.blockchain.lock().unwrap();
+                let immature = blockchain.immature_coinbases(&blockchain.tip());
+                std::mem::drop(blockchain);
                 let mut stateWitness = self.stateWitness.lock().unwrap();
                 if stateWitness.States.keys().len() > 0 {
                     for state in stateWitness.States.keys() {
                         //state check to avoid double spent
                         //State with witness: (prev TX Hash, prev Output Index) <-> (Output Value, Recipient Addr, Prime_number, Witness)
-                        if stateWitness.States.get(state).unwrap().1 == self.local_address {
+                        if stateWitness.States.get(state).unwrap().1 == Script::PayToPubkeyHash(self.local_address) && !immature.contains(&state.0) {
                             let tx_hash = state.0;
                             let output_index = state.1;
                             let prime = stateWitness.States.get(state).unwrap().2;
@@ -401,7 +585,7 @@ impl Context {
                 let mut pre_index = Vec::<u32>::new();
                 let mut witness_vec = Vec::<witness>::new();
                 //output
-                let mut out_value = Vec::<f32>::new();
+                let mut out_value = Vec::<u64>::new();
                 let mut recp_addr = Vec::<H160>::new();
 
                 for Iteration in myStateWitness.iter() {
@@ -409,23 +593,21 @@ impl Context {
                     pre_index.push(Iteration.1);
                     witness_vec.push(witness{prime_number:Iteration.2,witness:Iteration.3,})
                 }
-                //recipient value
-                let mut dest_value:f32 = 0.0;
-                if all_value > 10.0 {
+                //recipient value, in whole coins
+                let mut dest_value: u64 = COIN;
+                if all_value > 10 * COIN {
                     let mut rng = rand::thread_rng();
                     let dest_ = rng.gen_range(1,10);
-                    dest_value = dest_ as f32;
+                    dest_value = dest_ * COIN;
                 }
-                else if all_value <= 10.0 && all_value >= 2.0 {
+                else if all_value <= 10 * COIN && all_value >= 2 * COIN {
                     let mut rng = rand::thread_rng();
-                    let dest_= rng.gen_range(1,all_value as usize);
-                    dest_value = dest_ as f32;
-                }
-                else{
-                    dest_value = 1.0;
+                    let dest_= rng.gen_range(1,all_value / COIN);
+                    dest_value = dest_ * COIN;
                 }
 
-                let rest_value = all_value - (dest_value as f32);
+                //the fee is whatever the outputs leave of the inputs, taken from the change
+                let rest_value = all_value.checked_sub(dest_value);
 
                 //recipient adresses
                 let mut rng = rand::thread_rng();
@@ -433,9 +615,11 @@ impl Context {
                 let dest_addr: H160 = other_address[num];
                 recp_addr.push(dest_addr);
 
-                if rest_value >= 0.0 {
+                if let Some(rest_value) = rest_value {
+                    let fee = std::cmp::min(TX_FEE, rest_value);
+                    let rest_value = rest_value - fee;
                     out_value.push(dest_value);
-                    if rest_value > 0.0 {
+                    if rest_value > 0 {
                         out_value.push(rest_value);
                         recp_addr.push(self.local_address);
                     }
@@ -447,24 +631,13 @@ impl Context {
 
                     //need to check signature before inserting to mempool
 
+                    let blockchain = self.blockchain.lock().unwrap();
+                    let tip = blockchain.tip();
                     let mut mempool = self.mempool.lock().unwrap();
                     let mut stateWitness = self.stateWitness.lock().unwrap();
-                    let mut valid = true;
-                    for input in transaction.Input.clone() {
-                        if tx_set.contains(&(input.prevTransaction,input.preOutputIndex)){
-                            valid = false;
-                        }
-                    }
-
-
-                    if (!mempool.Transactions.contains_key(&SignedTransaction.hash()))
-                        && SignedTransaction.verifySignedTransaction()
-                        && stateWitness.ifNotDoubleSpent(&SignedTransaction.transaction.Input,&self.blockchain.lock().unwrap().tip()) //TODO DONE
-                        && valid{
-                        mempool.insert(&SignedTransaction);
-                        for input in transaction.Input.clone() {
-                            tx_set.insert((input.prevTransaction,input.preOutputIndex));
-                        }
+                    let fee = validation::transaction_fee(&transaction, &stateWitness).ok().flatten().unwrap_or(0);
+                    if validation::check_transaction(&SignedTransaction, &blockchain, &stateWitness, &tip).is_ok()
+                        && mempool.add(&SignedTransaction, fee).is_ok(){
                         tx_counter = tx_counter + 1;
                         info!("TXG: TX GENERATED");
                         //println!("{:?}",tx_counter);
@@ -475,12 +648,13 @@ impl Context {
                             println!("TXG: MEMPOOL KEYS:{:?}", key);//, mempool.Transactions.get(key).unwrap().transaction.Input, mempool.Transactions.get(key).unwrap().transaction.Output);
                         }
                         //txHash.push(SignedTransaction.hash().clone());
-                        block_set.insert(self.blockchain.lock().unwrap().tip.0);
+                        block_set.insert(tip);
                         self.server.broadcast(Message::NewTransactionHashes(txHash));
-                        println!("TXG: {:?} PAID {:?} {:?} BTC", self.local_address, dest_addr,dest_value);
+                        println!("TXG: {:?} PAID {:?} {:?} BTC", self.local_address, dest_addr,dest_value / COIN);
                     }
                     std::mem::drop(mempool);
                     std::mem::drop(stateWitness);
+                    std::mem::drop(blockchain);
                 }
 
             }
@@ -499,8 +673,14 @@ impl Context {
 }
 
 
-//generally generate a transaction without signature.
-pub fn generate_transaction(preHash:&Vec<H256>, preIndex:&Vec<u32>, witness: &Vec<witness>, outValue:&Vec<f32>, recpAddress:&Vec<H160>) -> Transaction {
+/// The unsigned coinbase of the block at `height`, paying `value` to `recpAddress`
+pub fn coinbase(height: u32, recpAddress: &H160, value: u64) -> SignedTransaction {
+    let transaction = generate_transaction(&vec![H256::default()], &vec![height], &vec![witness::default()], &vec![value], &vec![*recpAddress]);
+    SignedTransaction{transaction: transaction, unlocks: Vec::new()}
+}
+
+//generally generate a transaction without signature, paying to the default script of each address.
+pub fn generate_transaction(preHash:&Vec<H256>, preIndex:&Vec<u32>, witness: &Vec<witness>, outValue:&Vec<u64>, recpAddress:&Vec<H160>) -> Transaction {
 
     let mut inputVec = Vec::<input>::new();
     let mut outputVec = Vec::<output>::new();
@@ -510,13 +690,14 @@ pub fn generate_transaction(preHash:&Vec<H256>, preIndex:&Vec<u32>, witness: &Ve
             prevTransaction : preHash[in_],
             preOutputIndex: preIndex[in_],
             witness: witness[in_].clone(),
+            relativeLock: 0,
         };
         inputVec.push(input);
     }
     let mut out_count = 0;
     for out_ in 0..outValue.len() {
         let output = output{
-            recpAddress : recpAddress[out_],
+            script : Script::PayToPubkeyHash(recpAddress[out_]),
             value : outValue[out_],
             index: out_count,
         };
@@ -524,7 +705,7 @@ pub fn generate_transaction(preHash:&Vec<H256>, preIndex:&Vec<u32>, witness: &Ve
         outputVec.push(output);
     }
 
-    return Transaction{Input : inputVec,Output : outputVec,};
+    return Transaction{Input : inputVec,Output : outputVec, lockTime: LockTime::None, replaceable: false,};
 }
 
 
@@ -549,12 +730,12 @@ A = g^(a_1*a_2*...*a_6)
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct StateWitness {
     //States with Witness
-    pub States: HashMap<(H256, u32),(f32, H160, u32, u128)>, //  (prev TX Hash, prev Output Index) <-> (Output Value, Recipient Addr, Prime_number, Witness)
+    pub States: HashMap<(H256, u32),(u64, Script, u32, u128)>, //  (prev TX Hash, prev Output Index) <-> (Output Value, Output Script, Prime_number, Witness)
     pub AccumulatorProof: HashMap<H256,u128>, // Block Hash <-> Accumulator
 }
 impl StateWitness {
     pub fn new() -> Self{
-        let states:HashMap<(H256, u32),(f32, H160, u32, u128)> = HashMap::new();
+        let states:HashMap<(H256, u32),(u64, Script, u32, u128)> = HashMap::new();
         let accumulator_proof:HashMap<H256, u128> = HashMap::new();
         return StateWitness{States: states, AccumulatorProof: accumulator_proof}
     }
@@ -584,10 +765,10 @@ impl StateWitness {
         return is_not_double_spent
     }
     // CODE FOR ADDING STATES
-    // ENTER TX HASH, OUTPUT INDEX, OUTPUT VALUE, RECP ADDR, PRIME NUMBER, WITNESS
-    pub fn addStates(&mut self, transaction_hash: H256, output_index: u32, output_value: f32, recp_address: H160, prime_number: u32, witness: u128) {
+    // ENTER TX HASH, OUTPUT INDEX, OUTPUT VALUE, OUTPUT SCRIPT, PRIME NUMBER, WITNESS
+    pub fn addStates(&mut self, transaction_hash: H256, output_index: u32, output_value: u64, script: Script, prime_number: u32, witness: u128) {
         if !self.States.contains_key(&(transaction_hash,output_index)){
-            self.States.insert((transaction_hash,output_index),(output_value,recp_address,prime_number,witness));
+            self.States.insert((transaction_hash,output_index),(output_value,script,prime_number,witness));
         }
     }
     // ENTER TX HASH, OUTPUT INDEX
@@ -603,11 +784,11 @@ impl StateWitness {
         }
     }
 
-    pub fn getAllStates(&self) -> Vec<(H256, u32, f32, H160, u32, u128)>{
-        let mut all_states = Vec::<(H256, u32, f32, H160, u32, u128)>::new();
+    pub fn getAllStates(&self) -> Vec<(H256, u32, u64, Script, u32, u128)>{
+        let mut all_states = Vec::<(H256, u32, u64, Script, u32, u128)>::new();
         let States = self.States.clone();
         for (key, values) in States.iter() {
-            all_states.push((key.0,key.1,values.0,values.1,values.2,values.3));
+            all_states.push((key.0,key.1,values.0,values.1.clone(),values.2,values.3));
         }
         return all_states;
     }
@@ -635,13 +816,13 @@ pub fn generate_random_signed_transaction_() -> SignedTransaction {
     rand_addr.push(rand_addr_H160);
     let mut rand_u32:u32 = rand::thread_rng().gen();
     let mut rand_u32_vec = [rand_u32].to_vec();
-    let mut rand_f32:f32 = rand::thread_rng().gen();
-    let mut rand_f32_vec = [rand_f32].to_vec();
+    let mut rand_value:u64 = rand::thread_rng().gen_range(0, COIN);
+    let mut rand_value_vec = [rand_value].to_vec();
     let mut rand_u128:u128 = rand::thread_rng().gen();
     let mut witness = witness{prime_number:rand_u32, witness: rand_u128,};
     let mut witness_vec = [witness].to_vec();
 
-    let mut transaction = generate_transaction(&new_hash_vec,&rand_u32_vec, &witness_vec,&rand_f32_vec,&rand_addr);
+    let mut transaction = generate_transaction(&new_hash_vec,&rand_u32_vec, &witness_vec,&rand_value_vec,&rand_addr);
     let key = key_pair::random();
     let signature = sign(&transaction,&key);
     let SignedTransaction = SignedTransaction::new(&transaction,&signature,&key.public_key());
@@ -649,3 +830,56 @@ pub fn generate_random_signed_transaction_() -> SignedTransaction {
     return SignedTransaction;
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::hash::tests::generate_random_hash;
+
+    fn spend(outpoints: &[(H256, u32)], values: Vec<u64>, replaceable: bool) -> SignedTransaction {
+        let preHash: Vec<H256> = outpoints.iter().map(|outpoint| outpoint.0).collect();
+        let preIndex: Vec<u32> = outpoints.iter().map(|outpoint| outpoint.1).collect();
+        let recipients = vec![generate_random_hash().into(); values.len()];
+        let mut transaction = generate_transaction(&preHash, &preIndex, &vec![witness::default(); outpoints.len()], &values, &recipients);
+        transaction.replaceable = replaceable;
+        let key = key_pair::random();
+        let signature = sign(&transaction, &key);
+        SignedTransaction::new(&transaction, &signature, &key.public_key())
+    }
+
+    #[test]
+    fn conflicts_and_replacement() {
+        let mut mempool = Mempool::new();
+        let coin = (generate_random_hash(), 0);
+        let original = spend(&[coin], vec![9 * COIN], true);
+        assert_eq!(mempool.add(&original, COIN), Ok(vec![]));
+        assert_eq!(mempool.add(&original, COIN), Err(MempoolError::Duplicate));
+        // a child of the original
+        let child = spend(&[(original.hash(), 0)], vec![8 * COIN], false);
+        assert_eq!(mempool.add(&child, COIN), Ok(vec![]));
+
+        // a replacement has to pay more than the original and its child together
+        let cheap = spend(&[coin], vec![8 * COIN], false);
+        assert_eq!(mempool.add(&cheap, 2 * COIN), Err(MempoolError::InsufficientFee));
+        let replacement = spend(&[coin], vec![7 * COIN], false);
+        let mut evicted = mempool.add(&replacement, 3 * COIN).unwrap();
+        evicted.sort();
+        let mut expected = vec![original.hash(), child.hash()];
+        expected.sort();
+        assert_eq!(evicted, expected);
+        assert_eq!(mempool.Transactions.len(), 1);
+        assert_eq!(mempool.Spent.get(&coin), Some(&replacement.hash()));
+        assert!(mempool.Spent.get(&(original.hash(), 0)).is_none());
+
+        // the replacement did not opt in
+        let another = spend(&[coin], vec![COIN], true);
+        assert_eq!(mempool.add(&another, 9 * COIN), Err(MempoolError::Conflict(replacement.hash())));
+
+        // a confirmed double spend evicts the mempool transaction and its descendants
+        let grandchild = spend(&[(replacement.hash(), 0)], vec![6 * COIN], false);
+        mempool.add(&grandchild, COIN).unwrap();
+        mempool.updateMempool(&vec![another]);
+        assert!(mempool.Transactions.is_empty());
+        assert!(mempool.Spent.is_empty() && mempool.Fees.is_empty());
+    }
+}
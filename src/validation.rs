@@ -0,0 +1,733 @@
+use crate::block::{Block, Header};
+use crate::blockchain::{Blockchain, MEDIAN_TIME_SPAN};
+use crate::crypto::hash::{H256, Hashable};
+use crate::crypto::merkle::MerkleTree;
+use crate::params::block_subsidy;
+use crate::script::{self, Script};
+use crate::transaction::{input, signature_digest, LockTime, SignedTransaction, StateWitness, Transaction};
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// Most blocks a relative lock may ask the spent output to be buried under
+pub const MAX_RELATIVE_LOCK: u32 = 0xffff;
+
+/// Why a transaction was rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TxError {
+    InvalidSignature,
+    /// An input's witness does not match the accumulator proof
+    InvalidWitness,
+    /// Coinbases are only valid as the first transaction of a block
+    Coinbase,
+    /// An input spends a coinbase that is not buried deep enough yet
+    ImmatureCoinbase(H256),
+    /// An input spends an output that is not in the state
+    UnknownInput(H256, u32),
+    /// An input's unlocking data does not satisfy the script of the output it spends
+    WrongOwner(H256, u32),
+    /// The lock time of the transaction is not reached yet
+    Locked,
+    /// An input spends an output that is not buried deep enough for its relative lock
+    RelativeLock(H256, u32),
+    /// An input's relative lock is above `MAX_RELATIVE_LOCK`
+    RelativeLockTooLong(H256, u32),
+    OutputsExceedInputs { inputs: u64, outputs: u64 },
+    /// The values of the outputs, or of the inputs, add up to more than a `u64` holds
+    ValueOverflow,
+}
+
+impl fmt::Display for TxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TxError::InvalidSignature => write!(f, "invalid signature"),
+            TxError::InvalidWitness => write!(f, "input witness does not match the accumulator proof"),
+            TxError::Coinbase => write!(f, "coinbase outside of a block"),
+            TxError::ImmatureCoinbase(hash) => write!(f, "spends coinbase {:?} before it matured", hash),
+            TxError::UnknownInput(hash, index) => write!(f, "input {:?}:{} is not in the state", hash, index),
+            TxError::WrongOwner(hash, index) => write!(f, "input {:?}:{} does not satisfy the script of its output", hash, index),
+            TxError::Locked => write!(f, "lock time not reached"),
+            TxError::RelativeLock(hash, index) => write!(f, "input {:?}:{} is still locked", hash, index),
+            TxError::RelativeLockTooLong(hash, index) => write!(f, "input {:?}:{} has a relative lock above {}", hash, index, MAX_RELATIVE_LOCK),
+            TxError::OutputsExceedInputs { inputs, outputs } => write!(f, "outputs of {} exceed inputs of {}", outputs, inputs),
+            TxError::ValueOverflow => write!(f, "values overflow"),
+        }
+    }
+}
+
+/// Why a block was rejected. `MissingParent` is not a consensus failure: the block passed every check
+/// that does not need its parent and can be kept as an orphan.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockError {
+    InvalidProofOfWork,
+    TooLarge { size: usize, limit: usize },
+    Empty,
+    NoCoinbase,
+    ExtraCoinbase(H256),
+    BadCoinbaseHeight { expected: u32, found: u32 },
+    CoinbaseTooLarge { value: u64, limit: u64 },
+    BadMerkleRoot,
+    DuplicateTransaction(H256),
+    DoubleSpend(H256, u32),
+    InvalidTransaction(H256, TxError),
+    MissingParent(H256),
+    /// The block, or the ancestor given, was found invalid before
+    InvalidAncestor(H256),
+    WrongDifficulty { expected: H256, found: H256 },
+    TimestampTooOld,
+    TimestampTooNew,
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockError::InvalidProofOfWork => write!(f, "hash does not meet the block's difficulty"),
+            BlockError::TooLarge { size, limit } => write!(f, "block is {} bytes, limit is {}", size, limit),
+            BlockError::Empty => write!(f, "block has no transactions"),
+            BlockError::NoCoinbase => write!(f, "first transaction is not a coinbase"),
+            BlockError::ExtraCoinbase(hash) => write!(f, "transaction {:?} is a second coinbase", hash),
+            BlockError::BadCoinbaseHeight { expected, found } => write!(f, "coinbase commits to height {}, expected {}", found, expected),
+            BlockError::CoinbaseTooLarge { value, limit } => write!(f, "coinbase pays {}, subsidy and fees only allow {}", value, limit),
+            BlockError::BadMerkleRoot => write!(f, "merkle root does not match the transactions"),
+            BlockError::DuplicateTransaction(hash) => write!(f, "transaction {:?} appears twice", hash),
+            BlockError::DoubleSpend(hash, index) => write!(f, "output {:?}:{} is spent twice", hash, index),
+            BlockError::InvalidTransaction(hash, e) => write!(f, "transaction {:?}: {}", hash, e),
+            BlockError::MissingParent(hash) => write!(f, "parent {:?} is unknown", hash),
+            BlockError::InvalidAncestor(hash) => write!(f, "block {:?} was found invalid", hash),
+            BlockError::WrongDifficulty { expected, found } => write!(f, "difficulty is {:?}, expected {:?}", found, expected),
+            BlockError::TimestampTooOld => write!(f, "timestamp is not after the median time of the last {} blocks", MEDIAN_TIME_SPAN),
+            BlockError::TimestampTooNew => write!(f, "timestamp is too far in the future"),
+        }
+    }
+}
+
+impl BlockError {
+    /// Whether every node rejects the block, whatever its state and its clock. Stateless nodes may lack the
+    /// inputs or witnesses a block spends, and clocks differ, so only these errors prove the sender misbehaved.
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            BlockError::InvalidTransaction(_, e) => match e {
+                TxError::InvalidSignature
+                | TxError::Coinbase
+                | TxError::ImmatureCoinbase(_)
+                | TxError::ValueOverflow
+                | TxError::Locked
+                | TxError::RelativeLock(_, _)
+                | TxError::RelativeLockTooLong(_, _) => true,
+                _ => false,
+            },
+            BlockError::CoinbaseTooLarge { .. } | BlockError::MissingParent(_) | BlockError::TimestampTooNew => false,
+            _ => true,
+        }
+    }
+}
+
+/// Check a transaction for inclusion in a child of `parent`, with its witnesses checked against the accumulator
+/// proof of `parent`. Every input has to be in the state, so that the fee is known.
+pub fn check_transaction(tx: &SignedTransaction, blockchain: &Blockchain, stateWitness: &StateWitness, parent: &H256) -> Result<(), TxError> {
+    if tx.transaction.isCoinbase() {
+        return Err(TxError::Coinbase);
+    }
+    if !tx.verifySignedTransaction() {
+        return Err(TxError::InvalidSignature);
+    }
+    check_maturity(&tx.transaction, &blockchain.immature_coinbases(parent))?;
+    check_relative_lock_bound(&tx.transaction)?;
+    let recent = blockchain.recent_transactions(parent, relative_lock_depth(std::iter::once(&tx.transaction)));
+    check_locks(&tx.transaction, blockchain, parent, &recent, &HashSet::new())?;
+    if !stateWitness.ifNotDoubleSpent(&tx.transaction.Input, parent) {
+        return Err(TxError::InvalidWitness);
+    }
+    for input in tx.transaction.Input.iter() {
+        if !stateWitness.States.contains_key(&(input.prevTransaction, input.preOutputIndex)) {
+            return Err(TxError::UnknownInput(input.prevTransaction, input.preOutputIndex));
+        }
+    }
+    check_ownership(tx, stateWitness, &HashMap::new())?;
+    check_conservation(&tx.transaction, stateWitness)?;
+    Ok(())
+}
+
+/// Value and script of the outputs created by transactions earlier in the same block, which are not in the state yet
+type BlockOutputs = HashMap<(H256, u32), (u64, Script)>;
+
+/// The value and script of the output `input` spends, if it is in the state or in `created`
+fn spent_output<'a>(input: &input, stateWitness: &'a StateWitness, created: &'a BlockOutputs) -> Option<(u64, &'a Script)> {
+    let key = (input.prevTransaction, input.preOutputIndex);
+    match stateWitness.States.get(&key) {
+        Some(state) => Some((state.0, &state.1)),
+        None => created.get(&key).map(|output| (output.0, &output.1)),
+    }
+}
+
+/// Each input has to satisfy the script of the output it spends; inputs that are not in the state are not checked
+fn check_ownership(tx: &SignedTransaction, stateWitness: &StateWitness, created: &BlockOutputs) -> Result<(), TxError> {
+    let digest = signature_digest(&tx.transaction);
+    for (input, unlock) in tx.transaction.Input.iter().zip(tx.unlocks.iter()) {
+        if let Some((_, script)) = spent_output(input, stateWitness, created) {
+            if !script::evaluate(script, unlock, digest.as_ref()) {
+                return Err(TxError::WrongOwner(input.prevTransaction, input.preOutputIndex));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Outputs may not overflow nor create more than the inputs hold; transactions with unknown inputs only have
+/// their outputs checked
+fn check_conservation(tx: &Transaction, stateWitness: &StateWitness) -> Result<(), TxError> {
+    transaction_fee(tx, stateWitness).map(|_| ())
+}
+
+/// Sum of the output values, or `None` if it overflows
+pub fn output_value(tx: &Transaction) -> Option<u64> {
+    tx.Output.iter().try_fold(0u64, |sum, output| sum.checked_add(output.value))
+}
+
+fn check_maturity(tx: &Transaction, immature: &HashSet<H256>) -> Result<(), TxError> {
+    for input in tx.Input.iter() {
+        if immature.contains(&input.prevTransaction) {
+            return Err(TxError::ImmatureCoinbase(input.prevTransaction));
+        }
+    }
+    Ok(())
+}
+
+fn check_relative_lock_bound(tx: &Transaction) -> Result<(), TxError> {
+    match tx.Input.iter().find(|input| input.relativeLock > MAX_RELATIVE_LOCK) {
+        Some(input) => Err(TxError::RelativeLockTooLong(input.prevTransaction, input.preOutputIndex)),
+        None => Ok(()),
+    }
+}
+
+/// How many blocks back `recent_transactions` has to look for the relative locks of `txs`: only outputs confirmed
+/// in the last `lock - 1` blocks can still be locked
+fn relative_lock_depth<'a>(txs: impl Iterator<Item = &'a Transaction>) -> u32 {
+    txs.flat_map(|tx| tx.Input.iter())
+        .map(|input| std::cmp::min(input.relativeLock, MAX_RELATIVE_LOCK))
+        .max()
+        .unwrap_or(0)
+        .saturating_sub(1)
+}
+
+/// Lock times are checked for inclusion in a child of `parent`, against its height and the median time past of
+/// `parent`. Relative locks compare the height of the child to the height the spent output was confirmed at, found
+/// in `recent`, the transactions of the last `relative_lock_depth` blocks; outputs of `unconfirmed` transactions,
+/// earlier in the same block, are confirmed by the child itself.
+fn check_locks(tx: &Transaction, blockchain: &Blockchain, parent: &H256, recent: &HashMap<H256, u32>, unconfirmed: &HashSet<H256>) -> Result<(), TxError> {
+    let height = blockchain.Blocks.get(parent).unwrap().1 + 1;
+    let reached = match tx.lockTime {
+        LockTime::None => true,
+        LockTime::Height(lock) => height >= lock,
+        LockTime::Time(lock) => blockchain.median_time_past(parent) >= lock,
+    };
+    if !reached {
+        return Err(TxError::Locked);
+    }
+    for input in tx.Input.iter().filter(|input| input.relativeLock > 0) {
+        let confirmed = if unconfirmed.contains(&input.prevTransaction) {
+            Some(height)
+        } else {
+            recent.get(&input.prevTransaction).cloned()
+        };
+        if let Some(confirmed) = confirmed {
+            if height - confirmed < input.relativeLock {
+                return Err(TxError::RelativeLock(input.prevTransaction, input.preOutputIndex));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The fee a transaction pays to the miner: value of the inputs minus value of the outputs, or `None` if an
+/// input is not in the state. Fails if the values overflow or the outputs exceed the inputs
+pub fn transaction_fee(tx: &Transaction, stateWitness: &StateWitness) -> Result<Option<u64>, TxError> {
+    block_transaction_fee(tx, stateWitness, &HashMap::new())
+}
+
+fn block_transaction_fee(tx: &Transaction, stateWitness: &StateWitness, created: &BlockOutputs) -> Result<Option<u64>, TxError> {
+    let outputs = output_value(tx).ok_or(TxError::ValueOverflow)?;
+    let mut inputs: u64 = 0;
+    for input in tx.Input.iter() {
+        let value = match spent_output(input, stateWitness, created) {
+            Some((value, _)) => value,
+            None => return Ok(None),
+        };
+        inputs = inputs.checked_add(value).ok_or(TxError::ValueOverflow)?;
+    }
+    match inputs.checked_sub(outputs) {
+        Some(fee) => Ok(Some(fee)),
+        None => Err(TxError::OutputsExceedInputs { inputs, outputs }),
+    }
+}
+
+/// Checks that only need the block itself.
+pub fn check_block_context_free(block: &Block, blockchain: &Blockchain) -> Result<(), BlockError> {
+    if block.hash() > block.getdifficulty() {
+        return Err(BlockError::InvalidProofOfWork);
+    }
+    let size = bincode::serialized_size(block).unwrap() as usize;
+    if size > blockchain.params.max_block_size {
+        return Err(BlockError::TooLarge { size, limit: blockchain.params.max_block_size });
+    }
+    let contents = &block.Content.content;
+    if contents.is_empty() {
+        return Err(BlockError::Empty);
+    }
+    if MerkleTree::new(contents).root() != block.Header.merkleRoot {
+        return Err(BlockError::BadMerkleRoot);
+    }
+    if !contents[0].transaction.isCoinbase() {
+        return Err(BlockError::NoCoinbase);
+    }
+    let mut tx_hashes = HashSet::<H256>::new();
+    let mut spent = HashSet::<(H256, u32)>::new();
+    for (i, content) in contents.iter().enumerate() {
+        if !tx_hashes.insert(content.hash()) {
+            return Err(BlockError::DuplicateTransaction(content.hash()));
+        }
+        // the coinbase spends nothing and is not signed
+        if i == 0 {
+            continue;
+        }
+        if content.transaction.isCoinbase() {
+            return Err(BlockError::ExtraCoinbase(content.hash()));
+        }
+        if output_value(&content.transaction).is_none() {
+            return Err(BlockError::InvalidTransaction(content.hash(), TxError::ValueOverflow));
+        }
+        if let Err(e) = check_relative_lock_bound(&content.transaction) {
+            return Err(BlockError::InvalidTransaction(content.hash(), e));
+        }
+        for input in content.transaction.Input.iter() {
+            if !spent.insert((input.prevTransaction, input.preOutputIndex)) {
+                return Err(BlockError::DoubleSpend(input.prevTransaction, input.preOutputIndex));
+            }
+        }
+        if !content.verifySignedTransaction() {
+            return Err(BlockError::InvalidTransaction(content.hash(), TxError::InvalidSignature));
+        }
+    }
+    Ok(())
+}
+
+/// Checks a header needs to join the header tree: proof of work, a known parent header, and difficulty and
+/// timestamp bounds. `now` is the network-adjusted time in milliseconds.
+pub fn check_header(header: &Header, blockchain: &Blockchain, now: u128) -> Result<(), BlockError> {
+    if header.hash() > header.difficulty {
+        return Err(BlockError::InvalidProofOfWork);
+    }
+    if blockchain.is_invalid(header) {
+        let invalid = if blockchain.invalid.contains(&header.parent) { header.parent } else { header.hash() };
+        return Err(BlockError::InvalidAncestor(invalid));
+    }
+    if !blockchain.Headers.contains_key(&header.parent) {
+        return Err(BlockError::MissingParent(header.parent));
+    }
+    let expected = blockchain.next_difficulty(&header.parent);
+    if header.difficulty != expected {
+        return Err(BlockError::WrongDifficulty { expected, found: header.difficulty });
+    }
+    if header.timestamp <= blockchain.median_time_past(&header.parent) {
+        return Err(BlockError::TimestampTooOld);
+    }
+    if header.timestamp > now + blockchain.params.max_future_drift {
+        return Err(BlockError::TimestampTooNew);
+    }
+    Ok(())
+}
+
+/// Header checks for a block, whose parent block has to be known as well.
+pub fn check_block_header(block: &Block, blockchain: &Blockchain, now: u128) -> Result<(), BlockError> {
+    if !blockchain.Blocks.contains_key(&block.getparent()) {
+        return Err(BlockError::MissingParent(block.getparent()));
+    }
+    check_header(&block.Header, blockchain, now)
+}
+
+/// Run every block rule. Spends are checked against the state, which is the state of the current tip: for a block
+/// on another branch they are left to `check_block_spends` when its branch is connected.
+pub fn check_block(block: &Block, blockchain: &Blockchain, stateWitness: &StateWitness, complete: bool, now: u128) -> Result<(), BlockError> {
+    check_block_context_free(block, blockchain)?;
+    check_block_header(block, blockchain, now)?;
+    let parent = block.getparent();
+    let height = blockchain.Blocks.get(&parent).unwrap().1 + 1;
+    let immature = blockchain.immature_coinbases(&parent);
+    // the blocks relative locks look back on are walked once for the whole block
+    let recent = blockchain.recent_transactions(&parent, relative_lock_depth(block.Content.content.iter().skip(1).map(|content| &content.transaction)));
+    let mut unconfirmed = HashSet::<H256>::new();
+    for content in block.Content.content.iter().skip(1) {
+        if let Err(e) = check_maturity(&content.transaction, &immature) {
+            return Err(BlockError::InvalidTransaction(content.hash(), e));
+        }
+        if let Err(e) = check_locks(&content.transaction, blockchain, &parent, &recent, &unconfirmed) {
+            return Err(BlockError::InvalidTransaction(content.hash(), e));
+        }
+        unconfirmed.insert(content.hash());
+    }
+    let coinbase = &block.Content.content[0].transaction;
+    if coinbase.Input[0].preOutputIndex != height {
+        return Err(BlockError::BadCoinbaseHeight { expected: height, found: coinbase.Input[0].preOutputIndex });
+    }
+    if parent != blockchain.tip() {
+        return Ok(());
+    }
+    for content in block.Content.content.iter().skip(1) {
+        if !stateWitness.ifNotDoubleSpent(&content.transaction.Input, &parent) {
+            return Err(BlockError::InvalidTransaction(content.hash(), TxError::InvalidWitness));
+        }
+    }
+    check_block_spends(block, blockchain, stateWitness, complete)
+}
+
+/// Check what the transactions of a block spend against `stateWitness`, the state of its parent: scripts, values
+/// and the coinbase. When the state is `complete`, as the archival node's accumulator is, every input has to be
+/// in it or be created earlier in the block. Otherwise the state may lag behind, and values can only be checked
+/// for inputs in the state: transactions with unknown inputs pay no fee, and the coinbase may claim the subsidy
+/// plus the fees of the others.
+pub fn check_block_spends(block: &Block, blockchain: &Blockchain, stateWitness: &StateWitness, complete: bool) -> Result<(), BlockError> {
+    let height = blockchain.Blocks.get(&block.getparent()).unwrap().1 + 1;
+    let mut fees: u64 = 0;
+    let mut created = BlockOutputs::new();
+    for content in block.Content.content.iter().skip(1) {
+        if complete {
+            if let Some(input) = content.transaction.Input.iter().find(|input| spent_output(input, stateWitness, &created).is_none()) {
+                return Err(BlockError::InvalidTransaction(content.hash(), TxError::UnknownInput(input.prevTransaction, input.preOutputIndex)));
+            }
+        }
+        if let Err(e) = check_ownership(content, stateWitness, &created) {
+            return Err(BlockError::InvalidTransaction(content.hash(), e));
+        }
+        let fee = match block_transaction_fee(&content.transaction, stateWitness, &created) {
+            Ok(fee) => fee.unwrap_or(0),
+            Err(e) => return Err(BlockError::InvalidTransaction(content.hash(), e)),
+        };
+        for output in content.transaction.Output.iter() {
+            created.insert((content.hash(), output.index), (output.value, output.script.clone()));
+        }
+        fees = match fees.checked_add(fee) {
+            Some(fees) => fees,
+            None => return Err(BlockError::InvalidTransaction(content.hash(), TxError::ValueOverflow)),
+        };
+    }
+    let value = match output_value(&block.Content.content[0].transaction) {
+        Some(value) => value,
+        None => return Err(BlockError::InvalidTransaction(block.Content.content[0].hash(), TxError::ValueOverflow)),
+    };
+    let limit = block_subsidy(height, &blockchain.params).saturating_add(fees);
+    if value > limit {
+        return Err(BlockError::CoinbaseTooLarge { value, limit });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{Content, Header};
+    use crate::blockchain::tests::temp_data_dir;
+    use crate::params::{ChainParams, COIN};
+    use crate::crypto::hash::H160;
+    use crate::transaction::{coinbase, generate_random_signed_transaction_, generate_transaction, sign, witness};
+    use crate::crypto::key_pair;
+    use crate::script::{RedeemUnlock, Script, Unlock};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    /// A block on the tip with a coinbase claiming the full subsidy in front of `content`
+    fn block_on(blockchain: &Blockchain, mut content: Vec<SignedTransaction>) -> Block {
+        let parent = blockchain.tip();
+        let height = blockchain.Blocks.get(&parent).unwrap().1 + 1;
+        content.insert(0, coinbase(height, &H160::default(), block_subsidy(height, &blockchain.params)));
+        Block {
+            Header: Header {
+                parent,
+                nonce: 0,
+                difficulty: blockchain.next_difficulty(&parent),
+                timestamp: blockchain.Blocks.get(&parent).unwrap().0.gettimestamp() + 1,
+                merkleRoot: MerkleTree::new(&content).root(),
+            },
+            Content: Content { content },
+        }
+    }
+
+    fn mine(mut block: Block) -> Block {
+        while block.hash() > block.getdifficulty() {
+            block.Header.nonce += 1;
+        }
+        block
+    }
+
+    #[test]
+    fn block_rules() {
+        let dir = temp_data_dir("validation");
+        let blockchain = Blockchain::new(&dir, ChainParams::default()).unwrap();
+        let stateWitness = StateWitness::new();
+        let now = blockchain.Blocks.get(&blockchain.tip()).unwrap().0.gettimestamp() + 1000;
+        let tx = generate_random_signed_transaction_();
+
+        // a node holding the whole state knows the random inputs of `tx` do not exist
+        let valid = mine(block_on(&blockchain, vec![]));
+        assert_eq!(check_block(&valid, &blockchain, &stateWitness, true, now), Ok(()));
+        let block = mine(block_on(&blockchain, vec![tx.clone()]));
+        let input = tx.transaction.Input[0];
+        assert_eq!(
+            check_block(&block, &blockchain, &stateWitness, true, now),
+            Err(BlockError::InvalidTransaction(tx.hash(), TxError::UnknownInput(input.prevTransaction, input.preOutputIndex)))
+        );
+        // a stateless node may just lag behind
+        assert_eq!(check_block(&block, &blockchain, &stateWitness, false, now), Ok(()));
+
+        let mut block = valid.clone();
+        while block.hash() <= block.getdifficulty() {
+            block.Header.nonce += 1;
+        }
+        assert_eq!(check_block(&block, &blockchain, &stateWitness, false, now), Err(BlockError::InvalidProofOfWork));
+
+        let mut block = block_on(&blockchain, vec![tx.clone()]);
+        block.Header.merkleRoot = H256::default();
+        assert_eq!(check_block(&mine(block), &blockchain, &stateWitness, false, now), Err(BlockError::BadMerkleRoot));
+
+        let block = mine(block_on(&blockchain, vec![tx.clone(), tx.clone()]));
+        assert_eq!(check_block(&block, &blockchain, &stateWitness, false, now), Err(BlockError::DuplicateTransaction(tx.hash())));
+
+        let mut other = generate_random_signed_transaction_();
+        other.transaction.Input = tx.transaction.Input.clone();
+        let block = mine(block_on(&blockchain, vec![tx.clone(), other]));
+        let input = tx.transaction.Input[0];
+        assert_eq!(check_block(&block, &blockchain, &stateWitness, false, now), Err(BlockError::DoubleSpend(input.prevTransaction, input.preOutputIndex)));
+
+        let mut forged = tx.clone();
+        forged.transaction.Output[0].value += 1;
+        let block = mine(block_on(&blockchain, vec![forged.clone()]));
+        assert_eq!(check_block(&block, &blockchain, &stateWitness, false, now), Err(BlockError::InvalidTransaction(forged.hash(), TxError::InvalidSignature)));
+
+        let mut block = block_on(&blockchain, vec![tx.clone()]);
+        block.Header.difficulty = blockchain.params.pow_limit;
+        let block = mine(block);
+        assert_eq!(
+            check_block(&block, &blockchain, &stateWitness, false, now),
+            Err(BlockError::WrongDifficulty { expected: blockchain.params.initial_difficulty, found: blockchain.params.pow_limit })
+        );
+
+        let mut block = block_on(&blockchain, vec![tx.clone()]);
+        block.Header.timestamp -= 1;
+        assert_eq!(check_block(&mine(block), &blockchain, &stateWitness, false, now), Err(BlockError::TimestampTooOld));
+
+        let mut block = block_on(&blockchain, vec![tx.clone()]);
+        block.Header.timestamp = now + blockchain.params.max_future_drift + 1;
+        assert_eq!(check_block(&mine(block), &blockchain, &stateWitness, false, now), Err(BlockError::TimestampTooNew));
+
+        let mut block = block_on(&blockchain, vec![tx.clone()]);
+        block.Header.parent = H256::default();
+        let parent = block.getparent();
+        assert_eq!(check_block(&mine(block), &blockchain, &stateWitness, false, now), Err(BlockError::MissingParent(parent)));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn with_content(mut block: Block, content: Vec<SignedTransaction>) -> Block {
+        block.Header.merkleRoot = MerkleTree::new(&content).root();
+        block.Content.content = content;
+        mine(block)
+    }
+
+    #[test]
+    fn coinbase_rules() {
+        let dir = temp_data_dir("coinbase");
+        let mut blockchain = Blockchain::new(&dir, ChainParams::default()).unwrap();
+        let stateWitness = StateWitness::new();
+        let now = blockchain.Blocks.get(&blockchain.tip()).unwrap().0.gettimestamp() + 1000;
+        let tx = generate_random_signed_transaction_();
+        let subsidy = block_subsidy(1, &blockchain.params);
+
+        let valid = block_on(&blockchain, vec![tx.clone()]);
+        let reward = valid.Content.content[0].clone();
+
+        let block = with_content(valid.clone(), vec![tx.clone()]);
+        assert_eq!(check_block(&block, &blockchain, &stateWitness, false, now), Err(BlockError::NoCoinbase));
+
+        let other = coinbase(1, &H160::default(), COIN);
+        let block = with_content(valid.clone(), vec![reward.clone(), other.clone()]);
+        assert_eq!(check_block(&block, &blockchain, &stateWitness, false, now), Err(BlockError::ExtraCoinbase(other.hash())));
+
+        let block = with_content(valid.clone(), vec![coinbase(2, &H160::default(), subsidy), tx.clone()]);
+        assert_eq!(check_block(&block, &blockchain, &stateWitness, false, now), Err(BlockError::BadCoinbaseHeight { expected: 1, found: 2 }));
+
+        let block = with_content(valid.clone(), vec![coinbase(1, &H160::default(), subsidy + 1), tx.clone()]);
+        assert_eq!(
+            check_block(&block, &blockchain, &stateWitness, false, now),
+            Err(BlockError::CoinbaseTooLarge { value: subsidy + 1, limit: subsidy })
+        );
+
+        // the coinbase of block 1 cannot be spent by block 2
+        let valid = mine(valid);
+        assert_eq!(check_block(&valid, &blockchain, &stateWitness, false, now), Ok(()));
+        blockchain.insert(&valid);
+        let key = key_pair::random();
+        let transaction = generate_transaction(&vec![reward.hash()], &vec![0], &vec![witness::default()], &vec![subsidy], &vec![H160::default()]);
+        let spend = SignedTransaction::new(&transaction, &sign(&transaction, &key), &key.public_key());
+        let block = mine(block_on(&blockchain, vec![spend.clone()]));
+        assert_eq!(
+            check_block(&block, &blockchain, &stateWitness, false, now),
+            Err(BlockError::InvalidTransaction(spend.hash(), TxError::ImmatureCoinbase(reward.hash())))
+        );
+        assert_eq!(check_transaction(&spend, &blockchain, &stateWitness, &valid.hash()), Err(TxError::ImmatureCoinbase(reward.hash())));
+        assert_eq!(check_transaction(&reward, &blockchain, &stateWitness, &valid.hash()), Err(TxError::Coinbase));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn value_rules() {
+        let dir = temp_data_dir("value_rules");
+        let blockchain = Blockchain::new(&dir, ChainParams::default()).unwrap();
+        let tip = blockchain.tip();
+        let now = blockchain.Blocks.get(&tip).unwrap().0.gettimestamp() + 1000;
+        let mut stateWitness = StateWitness::new();
+        let prev = H256::from([1u8; 32]);
+        let key = key_pair::random();
+        stateWitness.addStates(prev, 0, 10 * COIN, Script::PayToPubkeyHash(key_pair::address(key.public_key().as_ref())), 7, 1);
+        let pay = |values: Vec<u64>| {
+            let recipients = vec![H160::default(); values.len()];
+            let transaction = generate_transaction(&vec![prev], &vec![0], &vec![witness { prime_number: 7, witness: 1 }], &values, &recipients);
+            SignedTransaction::new(&transaction, &sign(&transaction, &key), &key.public_key())
+        };
+
+        let tx = pay(vec![9 * COIN, COIN / 2]);
+        assert_eq!(check_transaction(&tx, &blockchain, &stateWitness, &tip), Ok(()));
+        assert_eq!(transaction_fee(&tx.transaction, &stateWitness), Ok(Some(COIN / 2)));
+        assert_eq!(
+            check_transaction(&pay(vec![10 * COIN + 1]), &blockchain, &stateWitness, &tip),
+            Err(TxError::OutputsExceedInputs { inputs: 10 * COIN, outputs: 10 * COIN + 1 })
+        );
+        // outputs that wrap around to less than the inputs
+        let overflow = pay(vec![u64::max_value(), 2]);
+        assert_eq!(check_transaction(&overflow, &blockchain, &stateWitness, &tip), Err(TxError::ValueOverflow));
+        let unknown = generate_random_signed_transaction_();
+        let input = unknown.transaction.Input[0];
+        assert_eq!(
+            check_transaction(&unknown, &blockchain, &stateWitness, &tip),
+            Err(TxError::UnknownInput(input.prevTransaction, input.preOutputIndex))
+        );
+
+        // the coinbase may collect the fee
+        let subsidy = block_subsidy(1, &blockchain.params);
+        let block = block_on(&blockchain, vec![tx.clone()]);
+        let block = with_content(block, vec![coinbase(1, &H160::default(), subsidy + COIN / 2), tx.clone()]);
+        assert_eq!(check_block(&block, &blockchain, &stateWitness, false, now), Ok(()));
+        let overspend = pay(vec![10 * COIN + 1]);
+        let block = with_content(block, vec![coinbase(1, &H160::default(), subsidy), overspend.clone()]);
+        assert_eq!(
+            check_block(&block, &blockchain, &stateWitness, false, now),
+            Err(BlockError::InvalidTransaction(overspend.hash(), TxError::OutputsExceedInputs { inputs: 10 * COIN, outputs: 10 * COIN + 1 }))
+        );
+        let block = with_content(block, vec![coinbase(1, &H160::default(), subsidy), overflow.clone()]);
+        assert_eq!(
+            check_block_context_free(&block, &blockchain),
+            Err(BlockError::InvalidTransaction(overflow.hash(), TxError::ValueOverflow))
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn ownership_rules() {
+        let dir = temp_data_dir("ownership_rules");
+        let blockchain = Blockchain::new(&dir, ChainParams::default()).unwrap();
+        let tip = blockchain.tip();
+        let now = blockchain.Blocks.get(&tip).unwrap().0.gettimestamp() + 1000;
+        let owner = key_pair::random();
+        let thief = key_pair::random();
+        let mut stateWitness = StateWitness::new();
+        let prev = H256::from([1u8; 32]);
+        stateWitness.addStates(prev, 0, 10 * COIN, Script::PayToPubkeyHash(key_pair::address(owner.public_key().as_ref())), 7, 1);
+        let transaction = generate_transaction(&vec![prev], &vec![0], &vec![witness { prime_number: 7, witness: 1 }], &vec![10 * COIN], &vec![H160::default()]);
+
+        let spend = SignedTransaction::new(&transaction, &sign(&transaction, &owner), &owner.public_key());
+        assert_eq!(check_transaction(&spend, &blockchain, &stateWitness, &tip), Ok(()));
+        // a valid signature by another key does not spend the output
+        let theft = SignedTransaction::new(&transaction, &sign(&transaction, &thief), &thief.public_key());
+        assert_eq!(check_transaction(&theft, &blockchain, &stateWitness, &tip), Err(TxError::WrongOwner(prev, 0)));
+        let block = mine(block_on(&blockchain, vec![theft.clone()]));
+        assert_eq!(
+            check_block(&block, &blockchain, &stateWitness, false, now),
+            Err(BlockError::InvalidTransaction(theft.hash(), TxError::WrongOwner(prev, 0)))
+        );
+
+        // a 2 of 2 multisig behind its script hash
+        let multisig = Script::Multisig { required: 2, public_keys: vec![owner.public_key().as_ref().to_vec(), thief.public_key().as_ref().to_vec()] };
+        let mut stateWitness = StateWitness::new();
+        stateWitness.addStates(prev, 0, 10 * COIN, multisig.pay_to_script(), 7, 1);
+        let signatures = |keys: &[&Ed25519KeyPair]| keys.iter().map(|key| sign(&transaction, key).as_ref().to_vec()).collect::<Vec<Vec<u8>>>();
+        let unlock = |signatures: Vec<Vec<u8>>| vec![Unlock::ScriptHash(multisig.clone(), RedeemUnlock::Multisig(signatures))];
+        let spend = SignedTransaction { transaction: transaction.clone(), unlocks: unlock(signatures(&[&owner, &thief])) };
+        assert_eq!(check_transaction(&spend, &blockchain, &stateWitness, &tip), Ok(()));
+        // one signature short: the redeem script fails before the output is even looked at
+        let short = SignedTransaction { transaction: transaction.clone(), unlocks: unlock(signatures(&[&owner])) };
+        assert_eq!(check_transaction(&short, &blockchain, &stateWitness, &tip), Err(TxError::InvalidSignature));
+        // the single-key unlock does not satisfy the script hash
+        assert_eq!(check_transaction(&theft, &blockchain, &stateWitness, &tip), Err(TxError::WrongOwner(prev, 0)));
+        // every input needs unlocking data
+        let unsigned = SignedTransaction { transaction: transaction.clone(), unlocks: vec![] };
+        assert_eq!(check_transaction(&unsigned, &blockchain, &stateWitness, &tip), Err(TxError::InvalidSignature));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn lock_rules() {
+        let dir = temp_data_dir("lock_rules");
+        let mut blockchain = Blockchain::new(&dir, ChainParams::default()).unwrap();
+        let owner = key_pair::random();
+        let funding = generate_random_signed_transaction_();
+        let b1 = mine(block_on(&blockchain, vec![funding.clone()]));
+        blockchain.insert(&b1);
+        let now = b1.gettimestamp() + 1000;
+        let mut stateWitness = StateWitness::new();
+        stateWitness.addStates(funding.hash(), 0, 10 * COIN, Script::PayToPubkeyHash(key_pair::address(owner.public_key().as_ref())), 7, 1);
+        let spend = |prev: H256, lockTime: LockTime, relativeLock: u32| {
+            let mut transaction = generate_transaction(&vec![prev], &vec![0], &vec![witness { prime_number: 7, witness: 1 }], &vec![COIN], &vec![H160::default()]);
+            transaction.lockTime = lockTime;
+            transaction.Input[0].relativeLock = relativeLock;
+            SignedTransaction::new(&transaction, &sign(&transaction, &owner), &owner.public_key())
+        };
+
+        // the funding output is at height 1: a child of b1 is one block above it
+        let tip = blockchain.tip();
+        assert_eq!(check_transaction(&spend(funding.hash(), LockTime::None, 1), &blockchain, &stateWitness, &tip), Ok(()));
+        assert_eq!(
+            check_transaction(&spend(funding.hash(), LockTime::None, 2), &blockchain, &stateWitness, &tip),
+            Err(TxError::RelativeLock(funding.hash(), 0))
+        );
+        assert_eq!(
+            check_transaction(&spend(funding.hash(), LockTime::None, MAX_RELATIVE_LOCK + 1), &blockchain, &stateWitness, &tip),
+            Err(TxError::RelativeLockTooLong(funding.hash(), 0))
+        );
+        let b2 = mine(block_on(&blockchain, vec![]));
+        blockchain.insert(&b2);
+        let tip = blockchain.tip();
+        assert_eq!(check_transaction(&spend(funding.hash(), LockTime::None, 2), &blockchain, &stateWitness, &tip), Ok(()));
+
+        // a child of b2 is at height 3
+        assert_eq!(check_transaction(&spend(funding.hash(), LockTime::Height(3), 0), &blockchain, &stateWitness, &tip), Ok(()));
+        assert_eq!(check_transaction(&spend(funding.hash(), LockTime::Height(4), 0), &blockchain, &stateWitness, &tip), Err(TxError::Locked));
+        let median = blockchain.median_time_past(&tip);
+        assert_eq!(check_transaction(&spend(funding.hash(), LockTime::Time(median), 0), &blockchain, &stateWitness, &tip), Ok(()));
+        assert_eq!(check_transaction(&spend(funding.hash(), LockTime::Time(median + 1), 0), &blockchain, &stateWitness, &tip), Err(TxError::Locked));
+
+        // an output created earlier in the same block is not a block old yet
+        let transaction = generate_transaction(&vec![funding.hash()], &vec![0], &vec![witness { prime_number: 7, witness: 1 }], &vec![10 * COIN], &vec![key_pair::address(owner.public_key().as_ref())]);
+        let chained = SignedTransaction::new(&transaction, &sign(&transaction, &owner), &owner.public_key());
+        let locked = spend(chained.hash(), LockTime::None, 1);
+        let block = mine(block_on(&blockchain, vec![chained.clone(), locked.clone()]));
+        assert_eq!(
+            check_block(&block, &blockchain, &stateWitness, false, now),
+            Err(BlockError::InvalidTransaction(locked.hash(), TxError::RelativeLock(chained.hash(), 0)))
+        );
+        let block = mine(block_on(&blockchain, vec![chained.clone(), spend(chained.hash(), LockTime::None, 0)]));
+        // the output is not in the state, but a node holding the whole state finds it earlier in the block
+        assert_eq!(check_block(&block, &blockchain, &stateWitness, true, now), Ok(()));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}